@@ -0,0 +1,389 @@
+//! Where `ContentStore` keeps file contents: in memory, or in a blob directory on disk
+//! with a small in-memory cache of recently used blobs.
+
+use anyhow::{Context, Result};
+use lru::LruCache;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{cache_dir, transfer::PartialContent, write_file_atomically, ContentHash};
+
+const BLOBS_DIR: &str = "blobs";
+/// Holds contents that are still being received in chunks.
+const PARTIAL_DIR: &str = "partial";
+/// Total size of the blobs kept in memory by the disk store.
+const CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) enum BlobStore {
+    Memory(HashMap<ContentHash, Vec<u8>>),
+    Disk(DiskBlobs),
+}
+
+#[derive(Debug)]
+pub(crate) struct DiskBlobs {
+    dir: PathBuf,
+    partial_dir: PathBuf,
+    hashes: HashSet<ContentHash>,
+    cache: RefCell<BlobCache>,
+}
+
+#[derive(Debug)]
+struct BlobCache {
+    blobs: LruCache<ContentHash, Vec<u8>>,
+    bytes: usize,
+}
+
+impl Default for BlobStore {
+    fn default() -> Self {
+        BlobStore::Memory(HashMap::new())
+    }
+}
+
+impl BlobStore {
+    /// Blobs are stored in the cache directory of `root`, sharded by the first byte of
+    /// their hash. Of the blobs left by an earlier run, only those in `keep` are kept.
+    pub fn on_disk(root: &Path, keep: &HashSet<ContentHash>) -> Result<Self> {
+        let dir = cache_dir(root).join(BLOBS_DIR);
+        let partial_dir = cache_dir(root).join(PARTIAL_DIR);
+        match std::fs::remove_dir_all(&partial_dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).context("Failed to clear partial blob directory")
+            }
+            _ => {}
+        }
+        std::fs::create_dir_all(&dir)?;
+        let mut disk = DiskBlobs {
+            dir,
+            partial_dir,
+            hashes: HashSet::new(),
+            cache: RefCell::new(BlobCache {
+                blobs: LruCache::unbounded(),
+                bytes: 0,
+            }),
+        };
+        // which blobs the other node has isn't persisted, so old ones are useless, except
+        // for the contents of unresolved conflicts in `keep`
+        for shard in std::fs::read_dir(&disk.dir).context("Failed to clear blob directory")? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                remove_stray(&shard.path())?;
+                continue;
+            }
+            for blob in std::fs::read_dir(shard.path())? {
+                let blob = blob?;
+                let hex = format!(
+                    "{}{}",
+                    shard.file_name().to_string_lossy(),
+                    blob.file_name().to_string_lossy()
+                );
+                match ContentHash::from_hex(hex) {
+                    Ok(hash) if keep.contains(&hash) && blob.file_type()?.is_file() => {
+                        disk.hashes.insert(hash);
+                    }
+                    _ => remove_stray(&blob.path())?,
+                }
+            }
+        }
+        Ok(BlobStore::Disk(disk))
+    }
+
+    /// Returns whether the blob wasn't in the store before.
+    pub fn insert(&mut self, hash: ContentHash, content: Vec<u8>) -> Result<bool> {
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs.insert(hash, content).is_none()),
+            BlobStore::Disk(disk) => {
+                if disk.hashes.contains(&hash) {
+                    return Ok(false);
+                }
+                let path = disk.blob_path(&hash);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                write_file_atomically(&path, &content, 0o600, false)
+                    .with_context(|| format!("Failed to write blob {hash}"))?;
+                disk.hashes.insert(hash);
+                disk.cache.get_mut().insert(hash, content);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Stores content assembled from chunks, moving it into place if it is on disk.
+    pub fn insert_partial(&mut self, hash: ContentHash, content: PartialContent) -> Result<bool> {
+        match (&mut *self, content) {
+            (_, PartialContent::Memory(content)) => self.insert(hash, content),
+            (BlobStore::Memory(_), PartialContent::File(path, _)) => {
+                let content = std::fs::read(&path)?;
+                std::fs::remove_file(&path)?;
+                self.insert(hash, content)
+            }
+            (BlobStore::Disk(disk), PartialContent::File(path, _)) => {
+                if !disk.hashes.insert(hash) {
+                    std::fs::remove_file(&path)?;
+                    return Ok(false);
+                }
+                let blob_path = disk.blob_path(&hash);
+                std::fs::create_dir_all(blob_path.parent().unwrap())?;
+                std::fs::rename(&path, &blob_path)
+                    .with_context(|| format!("Failed to move blob {hash} into place"))?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Where content received in chunks is assembled, `None` to keep it in memory.
+    pub fn partial_path(&self, hash: &ContentHash) -> Option<PathBuf> {
+        match self {
+            BlobStore::Memory(_) => None,
+            BlobStore::Disk(disk) => Some(disk.partial_dir.join(hash.to_hex().as_str())),
+        }
+    }
+
+    pub fn get(&self, hash: &ContentHash) -> Result<Option<Cow<'_, [u8]>>> {
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs.get(hash).map(|b| Cow::Borrowed(&b[..]))),
+            BlobStore::Disk(disk) => {
+                if !disk.hashes.contains(hash) {
+                    return Ok(None);
+                }
+                let mut cache = disk.cache.borrow_mut();
+                if let Some(content) = cache.blobs.get(hash) {
+                    return Ok(Some(Cow::Owned(content.clone())));
+                }
+                let path = disk.blob_path(hash);
+                let content = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read blob {}", path.display()))?;
+                cache.insert(*hash, content.clone());
+                Ok(Some(Cow::Owned(content)))
+            }
+        }
+    }
+
+    /// Reads `len` bytes at `offset` without loading the whole blob.
+    pub fn read_at(&self, hash: &ContentHash, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        match self {
+            BlobStore::Memory(blobs) => {
+                let Some(blob) = blobs.get(hash) else {
+                    return Ok(None);
+                };
+                let range = offset as usize..(offset + len) as usize;
+                let chunk = blob.get(range).context("Read past the end of a blob")?;
+                Ok(Some(chunk.to_vec()))
+            }
+            BlobStore::Disk(disk) => {
+                if !disk.hashes.contains(hash) {
+                    return Ok(None);
+                }
+                read_file_at(&disk.blob_path(hash), offset, len).map(Some)
+            }
+        }
+    }
+
+    pub fn open(&self, hash: &ContentHash) -> Result<Option<Box<dyn Read + '_>>> {
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs
+                .get(hash)
+                .map(|blob| Box::new(&blob[..]) as Box<dyn Read>)),
+            BlobStore::Disk(disk) => {
+                if !disk.hashes.contains(hash) {
+                    return Ok(None);
+                }
+                let path = disk.blob_path(hash);
+                let file = File::open(&path)
+                    .with_context(|| format!("Failed to open blob {}", path.display()))?;
+                Ok(Some(Box::new(file)))
+            }
+        }
+    }
+
+    pub fn hashes(&self) -> Box<dyn Iterator<Item = ContentHash> + '_> {
+        match self {
+            BlobStore::Memory(blobs) => Box::new(blobs.keys().copied()),
+            BlobStore::Disk(disk) => Box::new(disk.hashes.iter().copied()),
+        }
+    }
+
+    pub fn content_len(&self, hash: &ContentHash) -> Result<Option<u64>> {
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs.get(hash).map(|blob| blob.len() as u64)),
+            BlobStore::Disk(disk) => {
+                if !disk.hashes.contains(hash) {
+                    return Ok(None);
+                }
+                Ok(Some(std::fs::metadata(disk.blob_path(hash))?.len()))
+            }
+        }
+    }
+
+    pub fn contains(&self, hash: &ContentHash) -> bool {
+        match self {
+            BlobStore::Memory(blobs) => blobs.contains_key(hash),
+            BlobStore::Disk(disk) => disk.hashes.contains(hash),
+        }
+    }
+
+    pub fn remove(&mut self, hash: &ContentHash) -> Result<()> {
+        match self {
+            BlobStore::Memory(blobs) => {
+                blobs.remove(hash);
+            }
+            BlobStore::Disk(disk) => {
+                if disk.hashes.remove(hash) {
+                    disk.cache.get_mut().remove(hash);
+                    std::fs::remove_file(disk.blob_path(hash))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the blobs `keep` returns false for, returns how many were removed.
+    pub fn retain(&mut self, mut keep: impl FnMut(&ContentHash) -> bool) -> Result<usize> {
+        match self {
+            BlobStore::Memory(blobs) => {
+                let before = blobs.len();
+                blobs.retain(|hash, _| keep(hash));
+                Ok(before - blobs.len())
+            }
+            BlobStore::Disk(disk) => {
+                let garbage: Vec<_> = disk.hashes.iter().filter(|h| !keep(h)).copied().collect();
+                for hash in &garbage {
+                    self.remove(hash)?;
+                }
+                Ok(garbage.len())
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            BlobStore::Memory(blobs) => blobs.len(),
+            BlobStore::Disk(disk) => disk.hashes.len(),
+        }
+    }
+}
+
+pub(crate) fn read_file_at(path: &Path, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = vec![0; len as usize];
+    file.read_exact(&mut chunk)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(chunk)
+}
+
+impl DiskBlobs {
+    fn blob_path(&self, hash: &ContentHash) -> PathBuf {
+        let hex = hash.to_hex();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+}
+
+impl BlobCache {
+    fn insert(&mut self, hash: ContentHash, content: Vec<u8>) {
+        // a blob that doesn't fit would evict everything else
+        if content.len() > CACHE_BYTES {
+            return;
+        }
+        self.bytes += content.len();
+        if let Some(old) = self.blobs.put(hash, content) {
+            self.bytes -= old.len();
+        }
+        while self.bytes > CACHE_BYTES {
+            let Some((_, evicted)) = self.blobs.pop_lru() else {
+                break;
+            };
+            self.bytes -= evicted.len();
+        }
+    }
+
+    fn remove(&mut self, hash: &ContentHash) {
+        if let Some(content) = self.blobs.pop(hash) {
+            self.bytes -= content.len();
+        }
+    }
+}
+
+/// Removes an entry of the blob directory that isn't a blob to keep.
+fn remove_stray(path: &Path) -> Result<()> {
+    let result = if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    result.with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_blobs() {
+        let root = tempfile::tempdir().unwrap();
+        let mut blobs = BlobStore::on_disk(root.path(), &HashSet::new()).unwrap();
+        let hash = blake3::hash(b"hello");
+        assert!(blobs.insert(hash, b"hello".to_vec()).unwrap());
+        assert!(!blobs.insert(hash, b"hello".to_vec()).unwrap());
+        let hex = hash.to_hex();
+        let path = cache_dir(root.path())
+            .join(BLOBS_DIR)
+            .join(&hex[..2])
+            .join(&hex[2..]);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+        // evicted from memory, read back from disk
+        let BlobStore::Disk(disk) = &mut blobs else {
+            unreachable!()
+        };
+        disk.cache.get_mut().remove(&hash);
+        assert_eq!(&*blobs.get(&hash).unwrap().unwrap(), b"hello");
+
+        let other = blake3::hash(b"other");
+        blobs.insert(other, b"other".to_vec()).unwrap();
+        assert_eq!(blobs.retain(|h| *h == hash).unwrap(), 1);
+        assert_eq!(blobs.len(), 1);
+
+        blobs.remove(&hash).unwrap();
+        assert!(!blobs.contains(&hash));
+        assert!(blobs.get(&hash).unwrap().is_none());
+        assert!(!path.exists());
+
+        // a new run only keeps the blobs it asks for
+        blobs.insert(hash, b"hello".to_vec()).unwrap();
+        blobs.insert(other, b"other".to_vec()).unwrap();
+        let blobs = BlobStore::on_disk(root.path(), &HashSet::from([hash])).unwrap();
+        assert_eq!(&*blobs.get(&hash).unwrap().unwrap(), b"hello");
+        assert!(!blobs.contains(&other));
+
+        // stray entries don't keep a node from starting
+        let dir = cache_dir(root.path()).join(BLOBS_DIR);
+        std::fs::write(dir.join("stray"), b"").unwrap();
+        std::fs::create_dir_all(dir.join(&hex[..2]).join("nested")).unwrap();
+        let blobs = BlobStore::on_disk(root.path(), &HashSet::from([hash])).unwrap();
+        assert_eq!(&*blobs.get(&hash).unwrap().unwrap(), b"hello");
+        assert!(!dir.join("stray").exists());
+        assert!(!dir.join(&hex[..2]).join("nested").exists());
+    }
+
+    #[test]
+    fn test_cache_evicts_by_size() {
+        let mut cache = BlobCache {
+            blobs: LruCache::unbounded(),
+            bytes: 0,
+        };
+        let big = vec![0; CACHE_BYTES / 2];
+        for i in 0..3u8 {
+            cache.insert(blake3::hash(&[i]), big.clone());
+        }
+        assert_eq!(cache.blobs.len(), 2);
+        assert_eq!(cache.bytes, CACHE_BYTES);
+        assert!(!cache.blobs.contains(&blake3::hash(&[0])));
+    }
+}
@@ -0,0 +1,371 @@
+//! What happens when a change from the other node meets a local change to the same path.
+//! Concurrent edits of a text file are merged first. Otherwise, by default both versions
+//! are kept: the local one stays in place and the other node's is written next to it as a
+//! conflict copy, which is then synced like any other file. A path the two nodes were
+//! left with different versions of isn't synced until the user resolves its conflict.
+
+use anyhow::{Context, Result};
+use bincode::{Decode, Encode};
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::{
+    safe_fs, ContentHash, ContentStore, FileChange, FileMetadata, FilePath, FsState, SyncOptions,
+};
+
+/// Larger files are not merged.
+const MAX_MERGE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// How a conflicting change from the other node is resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the local version, and write the other node's next to it as
+    /// `name.fync-conflict-<host>-<timestamp>.ext`.
+    #[default]
+    KeepBoth,
+    /// Keep the local version and drop the other node's.
+    LocalWins,
+    /// Replace the local version with the other node's.
+    RemoteWins,
+    /// Keep the version modified last. Changes without a time, like removals, lose.
+    NewestWins,
+}
+
+impl ConflictPolicy {
+    /// The same policy as seen from the other node, so both nodes keep the same version.
+    pub fn for_other_node(self) -> Self {
+        match self {
+            ConflictPolicy::LocalWins => ConflictPolicy::RemoteWins,
+            ConflictPolicy::RemoteWins => ConflictPolicy::LocalWins,
+            policy => policy,
+        }
+    }
+}
+
+/// How concurrent edits of a text file are merged, before the conflict policy is applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MergeMode {
+    /// Don't merge, always apply the conflict policy.
+    Off,
+    /// Write merges where the edits don't overlap, and apply the conflict policy
+    /// otherwise.
+    #[default]
+    Clean,
+    /// Write every merge, with git-style conflict markers where the edits overlap.
+    Markers,
+}
+
+/// A change from the other node that conflicted with a local one.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Conflict {
+    pub path: FilePath,
+    pub resolution: Resolution,
+    /// The version both nodes started from, unless the path was created on both.
+    pub base: Option<FileMetadata>,
+    /// The local version when the change arrived.
+    pub local: Option<FileMetadata>,
+    /// The version of the other node.
+    pub remote: Option<FileMetadata>,
+}
+
+impl Conflict {
+    pub(crate) fn new(
+        path: FilePath,
+        change: &FileChange,
+        local: Option<FileMetadata>,
+        resolution: Resolution,
+    ) -> Self {
+        Conflict {
+            path,
+            resolution,
+            base: change.old_meta().cloned(),
+            local,
+            remote: change.new_meta().cloned(),
+        }
+    }
+
+    /// Whether the nodes were left with different versions. Changes to the path are then
+    /// held back until the conflict is resolved, so neither version silently wins.
+    pub fn diverged(&self) -> bool {
+        self.resolution != Resolution::KeptRemote
+    }
+
+    pub(crate) fn content_hashes(&self) -> impl Iterator<Item = ContentHash> + '_ {
+        [&self.base, &self.local, &self.remote]
+            .into_iter()
+            .flatten()
+            .filter_map(FileMetadata::content_hash)
+    }
+}
+
+/// How the user resolves a conflict.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum ConflictChoice {
+    /// Keep the local file as it is now.
+    Ours,
+    /// Take the version of the other node.
+    Theirs,
+    /// Take the content of this file, outside the root.
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum Resolution {
+    /// The local version was kept, the other node's was dropped.
+    KeptLocal,
+    /// The other node's version replaced the local one.
+    KeptRemote,
+    /// The local version was kept, the other node's was written to `copy`.
+    KeptBoth { copy: FilePath },
+    /// Both edits were merged into the local file. Written back to the local file, so it
+    /// is sent to the other node like a local edit.
+    Merged,
+    /// Both edits were merged, with conflict markers where they overlap.
+    MergedWithMarkers,
+}
+
+/// Resolves `change` to `file_path`, which conflicted with the local version.
+/// `remote_mtime` is when the other node last modified the file, in nanoseconds.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn resolve(
+    state: &mut FsState,
+    file_path: &FilePath,
+    change: &FileChange,
+    remote_mtime: Option<i64>,
+    peer_host: &str,
+    root: &Path,
+    options: &SyncOptions,
+    content_store: &mut ContentStore,
+) -> Result<Resolution> {
+    if let Some(resolution) = merge(file_path, change, root, options, content_store)? {
+        return Ok(resolution);
+    }
+    let remote_wins = match options.conflict_policy {
+        ConflictPolicy::KeepBoth => {
+            return keep_both(file_path, change, peer_host, root, options, content_store)
+        }
+        ConflictPolicy::LocalWins => false,
+        ConflictPolicy::RemoteWins => true,
+        ConflictPolicy::NewestWins => {
+            let local_mtime = local_mtime(&file_path.to_absolute(root))?;
+            match (remote_mtime, local_mtime) {
+                (Some(remote), Some(local)) => remote > local,
+                // a local removal loses to a remote edit
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        }
+    };
+    if remote_wins && state.force_change_to_disk(file_path, change, root, options, content_store)? {
+        return Ok(Resolution::KeptRemote);
+    }
+    Ok(Resolution::KeptLocal)
+}
+
+fn keep_both(
+    file_path: &FilePath,
+    change: &FileChange,
+    peer_host: &str,
+    root: &Path,
+    options: &SyncOptions,
+    content_store: &mut ContentStore,
+) -> Result<Resolution> {
+    // removals and directories leave nothing to keep
+    let Some(meta) = change
+        .new_meta()
+        .filter(|meta| meta.content_hash().is_some())
+    else {
+        return Ok(Resolution::KeptLocal);
+    };
+    let timestamp = format_timestamp(SystemTime::now());
+    let mut copy = conflict_copy_path(file_path, peer_host, &timestamp, 0);
+    let mut attempt = 0;
+    while std::fs::symlink_metadata(copy.to_absolute(root)).is_ok() {
+        attempt += 1;
+        copy = conflict_copy_path(file_path, peer_host, &timestamp, attempt);
+    }
+    if !meta.write_to_disk(root, &copy, None, options, content_store)? {
+        warn!(?file_path, "Failed to write the conflict copy");
+        return Ok(Resolution::KeptLocal);
+    }
+    Ok(Resolution::KeptBoth { copy })
+}
+
+/// Merges a modification of a text file with the local edit, using the version both
+/// started from. Returns `None` if the versions can't be merged.
+fn merge(
+    file_path: &FilePath,
+    change: &FileChange,
+    root: &Path,
+    options: &SyncOptions,
+    content_store: &mut ContentStore,
+) -> Result<Option<Resolution>> {
+    let FileChange::Modified {
+        old_meta: FileMetadata::File {
+            content_hash: base, ..
+        },
+        new_meta: FileMetadata::File {
+            content_hash: theirs,
+            ..
+        },
+    } = change
+    else {
+        return Ok(None);
+    };
+    if options.merge == MergeMode::Off {
+        return Ok(None);
+    }
+    let full_path = file_path.to_absolute(root);
+    let Some(FileMetadata::File {
+        content_hash: ours, ..
+    }) = FileMetadata::from_fs(&full_path, options, content_store)?
+    else {
+        return Ok(None);
+    };
+    let [Some(base), Some(ours), Some(theirs)] =
+        [base, &ours, theirs].map(|hash| text_content(content_store, hash))
+    else {
+        return Ok(None);
+    };
+    let merged = diffy::MergeOptions::new()
+        .set_conflict_style(diffy::ConflictStyle::Merge)
+        .merge(&base, &ours, &theirs);
+    let (merged, resolution) = match merged {
+        Ok(merged) => (merged, Resolution::Merged),
+        Err(merged) if options.merge == MergeMode::Markers => {
+            (merged, Resolution::MergedWithMarkers)
+        }
+        Err(_) => return Ok(None),
+    };
+    let entry = safe_fs::open_entry(root, file_path, false)?;
+    let mode = entry.file_mode().context("Merged file went away")?;
+    entry.write_file(merged.as_bytes(), mode, options.fsync)?;
+    Ok(Some(resolution))
+}
+
+/// The content, if it is available and looks like text.
+fn text_content(content_store: &ContentStore, hash: &ContentHash) -> Option<String> {
+    if content_store.content_len(hash).ok()? > MAX_MERGE_BYTES {
+        return None;
+    }
+    let content = String::from_utf8(content_store.get(hash).ok()?.into_owned()).ok()?;
+    (!content.contains('\0')).then_some(content)
+}
+
+/// Modification time of what is at `path`, in nanoseconds.
+fn local_mtime(path: &Path) -> Result<Option<i64>> {
+    use std::os::unix::fs::MetadataExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(
+            metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `dir/name.ext` becomes `dir/name.fync-conflict-<host>-<timestamp>.ext`, with a
+/// counter after the timestamp when that is taken.
+fn conflict_copy_path(file_path: &FilePath, host: &str, timestamp: &str, attempt: u32) -> FilePath {
+    let path = Path::new(file_path.0.as_ref());
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // a leading dot starts a hidden name, not an extension
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name.as_ref(), ""),
+    };
+    let host: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let suffix = if attempt == 0 {
+        String::new()
+    } else {
+        format!("-{attempt}")
+    };
+    let name = format!("{stem}.fync-conflict-{host}-{timestamp}{suffix}{extension}");
+    let copy = path.with_file_name(name);
+    FilePath(copy.to_string_lossy().as_ref().into())
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Name of this machine, used in the conflict copies the other node writes.
+pub(crate) fn hostname() -> String {
+    let name = rustix::system::uname()
+        .nodename()
+        .to_string_lossy()
+        .into_owned();
+    if name.is_empty() {
+        "unknown".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_conflict_copy_path() {
+        let copy = |path: &str, attempt| {
+            conflict_copy_path(
+                &FilePath(path.into()),
+                "my host",
+                "20241017-120000",
+                attempt,
+            )
+            .0
+        };
+        assert_eq!(
+            &*copy("src/main.rs", 0),
+            "src/main.fync-conflict-my_host-20241017-120000.rs"
+        );
+        assert_eq!(
+            &*copy(".env", 0),
+            ".env.fync-conflict-my_host-20241017-120000"
+        );
+        assert_eq!(
+            &*copy("Makefile", 2),
+            "Makefile.fync-conflict-my_host-20241017-120000-2"
+        );
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1729166400 + 3723)),
+            "20241017-130203"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! A socket in the cache directory, through which `fync conflicts` talks to the node
+//! syncing that root. Requests are handled in the node's event loop, between messages of
+//! the other node.
+
+use anyhow::{bail, Context, Result};
+use bincode::{config::standard, Decode, Encode};
+use crossbeam_channel::Sender;
+use std::{
+    io::BufReader,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+use crate::{
+    cache_dir, Conflict, ConflictChoice, ContentStore, FileMetadata, FilePath, FrameReader,
+    FrameWriter, Node, NodeMessage, SyncOptions,
+};
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub enum ControlRequest {
+    ListConflicts,
+    ShowConflict {
+        path: String,
+    },
+    ResolveConflict {
+        path: String,
+        choice: ConflictChoice,
+    },
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub enum ControlResponse {
+    Conflicts(Vec<Conflict>),
+    Versions {
+        base: ConflictVersion,
+        local: ConflictVersion,
+        remote: ConflictVersion,
+    },
+    Resolved,
+    Error(String),
+}
+
+/// One side of a conflict, as shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum ConflictVersion {
+    /// The path doesn't exist in this version.
+    Absent,
+    File(Vec<u8>),
+    Symlink(String),
+    Directory,
+    /// The content is no longer stored.
+    Unavailable,
+}
+
+/// A request, and where its response goes.
+pub type ControlCall = (ControlRequest, Sender<ControlResponse>);
+
+fn socket_path(root: &Path) -> PathBuf {
+    cache_dir(root).join("control.sock")
+}
+
+/// Accepts connections on the socket of `root` in a thread, and passes their requests on.
+/// Only the user running the node can connect, as requests can read and write any file in
+/// the root.
+pub fn serve_control(root: &Path, calls: Sender<ControlCall>) -> Result<()> {
+    let path = socket_path(root);
+    let dir = cache_dir(root);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))
+        .with_context(|| format!("Failed to restrict access to {}", dir.display()))?;
+    // left behind by an earlier session
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(Into::into)
+                .and_then(|stream| serve_connection(stream, &calls));
+            if let Err(e) = result {
+                warn!("Control connection failed: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream, calls: &Sender<ControlCall>) -> Result<()> {
+    // in case the modes of the cache directory were loosened since
+    let peer = rustix::net::sockopt::get_socket_peercred(&stream)?;
+    if peer.uid != rustix::process::geteuid() {
+        bail!("Refused a connection from uid {}", peer.uid.as_raw());
+    }
+    let mut reader = FrameReader::new(BufReader::new(stream.try_clone()?));
+    let mut writer = FrameWriter::new(stream);
+    while let Some(frame) = reader.read_frame()? {
+        let (request, _) = bincode::decode_from_slice(&frame, standard())?;
+        let (response_tx, response_rx) = crossbeam_channel::bounded(1);
+        calls.send((request, response_tx))?;
+        let response = response_rx.recv()?;
+        writer.write_frame(&bincode::encode_to_vec(response, standard())?)?;
+    }
+    Ok(())
+}
+
+/// Sends `request` to the node syncing `root`.
+pub fn send_control_request(root: &Path, request: &ControlRequest) -> Result<ControlResponse> {
+    let stream = UnixStream::connect(socket_path(root))
+        .with_context(|| format!("No fync session is syncing {}", root.display()))?;
+    let mut writer = FrameWriter::new(stream.try_clone()?);
+    writer.write_frame(&bincode::encode_to_vec(request, standard())?)?;
+    let mut reader = FrameReader::new(BufReader::new(stream));
+    let frame = reader
+        .read_frame()?
+        .context("Session ended without a response")?;
+    let (response, _) = bincode::decode_from_slice(&frame, standard())?;
+    Ok(response)
+}
+
+/// Handles `request`, and returns the response with a message for the other node.
+pub fn handle_control_request(
+    node: &mut Node,
+    request: ControlRequest,
+    root: &Path,
+    options: &SyncOptions,
+    content_store: &mut ContentStore,
+) -> (ControlResponse, Option<NodeMessage>) {
+    let result = match request {
+        ControlRequest::ListConflicts => {
+            Ok((ControlResponse::Conflicts(node.conflicts().to_vec()), None))
+        }
+        ControlRequest::ShowConflict { path } => file_path(&path)
+            .and_then(|file_path| show_conflict(node, &file_path, root, options, content_store))
+            .map(|response| (response, None)),
+        ControlRequest::ResolveConflict { path, choice } => file_path(&path)
+            .and_then(|file_path| {
+                node.resolve_conflict(&file_path, &choice, root, options, content_store)
+            })
+            .map(|message| (ControlResponse::Resolved, message)),
+    };
+    result.unwrap_or_else(|e| (ControlResponse::Error(format!("{e:#}")), None))
+}
+
+fn file_path(path: &str) -> Result<FilePath> {
+    let file_path = FilePath(Arc::from(path.trim_end_matches('/')));
+    if file_path.validate().is_err() {
+        bail!("Invalid path {path:?}, expected a path relative to the root");
+    }
+    Ok(file_path)
+}
+
+fn show_conflict(
+    node: &Node,
+    file_path: &FilePath,
+    root: &Path,
+    options: &SyncOptions,
+    content_store: &mut ContentStore,
+) -> Result<ControlResponse> {
+    let conflict = node
+        .conflicts()
+        .iter()
+        .find(|conflict| conflict.path == *file_path)
+        .with_context(|| format!("{file_path:?} has no conflict"))?;
+    let local = FileMetadata::from_fs(&file_path.to_absolute(root), options, content_store)?;
+    let version = |meta: Option<&FileMetadata>| match meta {
+        None => ConflictVersion::Absent,
+        Some(FileMetadata::File { content_hash, .. }) => content_store
+            .get(content_hash)
+            .map_or(ConflictVersion::Unavailable, |content| {
+                ConflictVersion::File(content.into_owned())
+            }),
+        Some(FileMetadata::Symlink { target }) => ConflictVersion::Symlink(target.to_string()),
+        Some(FileMetadata::Directory) => ConflictVersion::Directory,
+    };
+    Ok(ControlResponse::Versions {
+        base: version(conflict.base.as_ref()),
+        local: version(local.as_ref()),
+        remote: version(conflict.remote.as_ref()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FsState;
+
+    #[test]
+    fn test_socket_roundtrip() {
+        let root = tempfile::tempdir().unwrap();
+        let (calls_tx, calls_rx) = crossbeam_channel::unbounded::<ControlCall>();
+        serve_control(root.path(), calls_tx).unwrap();
+        std::thread::spawn(move || {
+            for (request, response_tx) in calls_rx {
+                assert!(matches!(request, ControlRequest::ListConflicts));
+                response_tx
+                    .send(ControlResponse::Conflicts(Vec::new()))
+                    .unwrap();
+            }
+        });
+        let response = send_control_request(root.path(), &ControlRequest::ListConflicts).unwrap();
+        assert!(matches!(response, ControlResponse::Conflicts(conflicts) if conflicts.is_empty()));
+        // other users can't connect
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&cache_dir(root.path())), 0o700);
+        assert_eq!(mode(&socket_path(root.path())), 0o600);
+
+        let other = tempfile::tempdir().unwrap();
+        assert!(send_control_request(other.path(), &ControlRequest::ListConflicts).is_err());
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let empty = || FsState {
+            files: Default::default(),
+        };
+        let mut node = Node::new(empty(), empty());
+        let mut content_store = ContentStore::default();
+        for path in ["../x", "/etc/passwd", "a/./b", ".fync/state", ""] {
+            let (response, message) = handle_control_request(
+                &mut node,
+                ControlRequest::ResolveConflict {
+                    path: path.to_string(),
+                    choice: ConflictChoice::Ours,
+                },
+                root.path(),
+                &SyncOptions::default(),
+                &mut content_store,
+            );
+            assert!(
+                matches!(&response, ControlResponse::Error(e) if e.contains("Invalid path")),
+                "{path}: {response:?}"
+            );
+            assert!(message.is_none());
+        }
+        assert!(file_path("dir/file/").is_ok());
+    }
+}
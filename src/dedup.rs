@@ -0,0 +1,173 @@
+//! Content-defined chunking. Chunk boundaries depend on the data around them, so a copy
+//! of a file, or a file that grew, shares most chunks with contents the other node
+//! already has, and only the chunks it lacks are sent.
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    sync::Arc,
+};
+
+use crate::ContentHash;
+
+const MIN_CHUNK_BYTES: u32 = 16 * 1024;
+const AVG_CHUNK_BYTES: u32 = 64 * 1024;
+const MAX_CHUNK_BYTES: u32 = 256 * 1024;
+/// Smaller contents are sent whole or as a delta.
+pub(crate) const MIN_DEDUP_BYTES: u64 = 4 * MAX_CHUNK_BYTES as u64;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkInfo {
+    pub hash: ContentHash,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// Where a chunk is in a content.
+#[derive(Debug, Clone, Copy, Encode, Decode)]
+pub struct ChunkSource {
+    #[bincode(with_serde)]
+    pub content: ContentHash,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// A content sent as chunks, most of which the receiver already has.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct ChunkedContent {
+    #[bincode(with_serde)]
+    pub hash: ContentHash,
+    pub parts: Vec<ChunkPart>,
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub enum ChunkPart {
+    /// A chunk the receiver has as part of another content.
+    Known {
+        #[bincode(with_serde)]
+        hash: ContentHash,
+        source: ChunkSource,
+    },
+    /// A chunk the receiver lacks, compressed with zstd.
+    New { len: u32, data: Vec<u8> },
+}
+
+impl ChunkPart {
+    /// Length of the chunk in the content.
+    pub fn len(&self) -> u64 {
+        match self {
+            ChunkPart::Known { source, .. } => source.len.into(),
+            ChunkPart::New { len, .. } => (*len).into(),
+        }
+    }
+}
+
+impl ChunkedContent {
+    /// Length of the content, or `None` if a part is longer than the chunks this node
+    /// makes, which only a misbehaving node sends.
+    pub fn content_len(&self) -> Option<u64> {
+        self.parts
+            .iter()
+            .map(|part| (part.len() <= MAX_CHUNK_BYTES.into()).then(|| part.len()))
+            .sum()
+    }
+}
+
+pub(crate) fn chunk_content(reader: impl Read) -> Result<Vec<ChunkInfo>> {
+    let chunker =
+        fastcdc::v2020::StreamCDC::new(reader, MIN_CHUNK_BYTES, AVG_CHUNK_BYTES, MAX_CHUNK_BYTES);
+    let mut chunks = Vec::new();
+    for chunk in chunker {
+        let chunk = chunk?;
+        chunks.push(ChunkInfo {
+            hash: blake3::hash(&chunk.data),
+            offset: chunk.offset,
+            len: chunk.length as u32,
+        });
+    }
+    Ok(chunks)
+}
+
+/// The chunks of contents, and which of them the other node has. Like `new_contents`,
+/// this is about a single peer.
+#[derive(Debug, Default)]
+pub(crate) struct DedupIndex {
+    /// Chunks of the contents large enough to dedup.
+    recipes: HashMap<ContentHash, Arc<[ChunkInfo]>>,
+    /// Contents the other node has that were already looked at.
+    peer_contents: HashSet<ContentHash>,
+    /// Where the other node has each chunk.
+    peer_chunks: HashMap<ContentHash, ChunkSource>,
+}
+
+impl DedupIndex {
+    pub fn recipe(&self, hash: &ContentHash) -> Option<Arc<[ChunkInfo]>> {
+        self.recipes.get(hash).cloned()
+    }
+
+    pub fn add_recipe(&mut self, hash: ContentHash, chunks: Vec<ChunkInfo>) -> Arc<[ChunkInfo]> {
+        let chunks: Arc<[ChunkInfo]> = chunks.into();
+        self.recipes.insert(hash, chunks.clone());
+        chunks
+    }
+
+    pub fn knows_peer_content(&self, hash: &ContentHash) -> bool {
+        self.peer_contents.contains(hash)
+    }
+
+    /// Records that the other node has `content`, and its chunks if it has any.
+    pub fn add_peer_content(&mut self, content: ContentHash, chunks: Option<&[ChunkInfo]>) {
+        self.peer_contents.insert(content);
+        for chunk in chunks.into_iter().flatten() {
+            self.peer_chunks.entry(chunk.hash).or_insert(ChunkSource {
+                content,
+                offset: chunk.offset,
+                len: chunk.len,
+            });
+        }
+    }
+
+    pub fn peer_chunk(&self, chunk: &ContentHash) -> Option<ChunkSource> {
+        self.peer_chunks.get(chunk).copied()
+    }
+
+    /// Forgets contents not in `live`, and the chunks found in them. Such a chunk may
+    /// also be in a live content, it is then sent again, which is merely wasteful.
+    pub fn retain(&mut self, live: &HashSet<ContentHash>) {
+        self.recipes.retain(|hash, _| live.contains(hash));
+        self.peer_contents.retain(|hash| live.contains(hash));
+        self.peer_chunks
+            .retain(|_, source| live.contains(&source.content));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundaries_follow_content() {
+        let mut data = Vec::new();
+        let mut hash = blake3::hash(b"seed");
+        while data.len() < 2 * 1024 * 1024 {
+            data.extend_from_slice(hash.as_bytes());
+            hash = blake3::hash(hash.as_bytes());
+        }
+        let chunks = chunk_content(&data[..]).unwrap();
+        let total: u64 = chunks.iter().map(|c| c.len as u64).sum();
+        assert_eq!(total, data.len() as u64);
+
+        // inserting at the start only changes the chunks around the insertion
+        let mut shifted = b"a few new bytes".to_vec();
+        shifted.extend_from_slice(&data);
+        let shifted_chunks = chunk_content(&shifted[..]).unwrap();
+        let known: HashSet<_> = chunks.iter().map(|c| c.hash).collect();
+        let new = shifted_chunks
+            .iter()
+            .filter(|c| !known.contains(&c.hash))
+            .count();
+        assert!(new <= 2, "{new} of {} chunks changed", shifted_chunks.len());
+    }
+}
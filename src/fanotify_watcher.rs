@@ -0,0 +1,331 @@
+//! Watches the root with a single fanotify mark on its whole filesystem, instead of an
+//! inotify watch on every directory. Nothing is set up per directory, so a huge tree
+//! isn't walked again and `max_user_watches` doesn't run out. Events name the
+//! directory by file handle and the entry in it by name. Marking a filesystem, and
+//! opening file handles, needs `CAP_SYS_ADMIN`.
+
+use fanotify::low_level::{
+    fanotify_init, fanotify_mark, AT_FDCWD, FAN_ATTRIB, FAN_CLASS_NOTIF, FAN_CLOEXEC,
+    FAN_CLOSE_WRITE, FAN_CREATE, FAN_DELETE, FAN_MARK_ADD, FAN_MARK_FILESYSTEM, FAN_MODIFY,
+    FAN_MOVE, FAN_NONBLOCK, FAN_ONDIR, FAN_Q_OVERFLOW, FAN_REPORT_DIR_FID, FAN_REPORT_NAME,
+    O_CLOEXEC, O_RDONLY,
+};
+use rustix::{
+    event::{PollFd, PollFlags},
+    fs::{Mode, OFlags, CWD},
+    io::Errno,
+};
+use std::{
+    ffi::OsStr,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+use tracing::error;
+
+use crate::{EventFilter, RefreshRequest};
+
+/// `FAN_REPORT_DFID_NAME`: events carry the handle of the directory and the name in it.
+const REPORT_DFID_NAME: u32 = FAN_REPORT_DIR_FID | FAN_REPORT_NAME;
+const EVENTS: u64 =
+    FAN_CREATE | FAN_DELETE | FAN_MOVE | FAN_MODIFY | FAN_CLOSE_WRITE | FAN_ATTRIB | FAN_ONDIR;
+/// Events that add or remove the entry they name, rather than change it.
+const ENTRY_EVENTS: u64 = FAN_CREATE | FAN_DELETE | FAN_MOVE;
+/// How long a read waits before checking whether the watcher was dropped.
+const STOP_CHECK_MS: i32 = 200;
+
+const METADATA_LEN: usize = 24;
+const INFO_HEADER_LEN: usize = 4;
+/// The header and the filesystem id come before the file handle.
+const FILE_HANDLE_OFFSET: usize = INFO_HEADER_LEN + 8;
+const FILE_HANDLE_HEADER_LEN: usize = 8;
+
+/// A fanotify group with a mark on the filesystem of the root, not read from yet.
+pub(crate) struct FanotifyGroup {
+    fd: OwnedFd,
+    /// Any directory on the filesystem, to open file handles with.
+    mount: OwnedFd,
+    root: PathBuf,
+}
+
+/// Reads the events of a [`FanotifyGroup`] until it is dropped.
+pub(crate) struct FanotifyWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// An event as the kernel reports it: the directory by file handle, and the name of
+/// the entry that changed in it.
+#[derive(Debug, PartialEq, Eq)]
+struct RawEvent {
+    mask: u64,
+    handle: Vec<u8>,
+    name: Vec<u8>,
+}
+
+impl FanotifyGroup {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let fd = fanotify_init(
+            FAN_CLASS_NOTIF | FAN_CLOEXEC | FAN_NONBLOCK | REPORT_DFID_NAME,
+            (O_RDONLY | O_CLOEXEC) as u32,
+        )?;
+        // SAFETY: fanotify_init just returned it, nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        fanotify_mark(
+            fd.as_raw_fd(),
+            FAN_MARK_ADD | FAN_MARK_FILESYSTEM,
+            EVENTS,
+            AT_FDCWD,
+            root,
+        )?;
+        let mount = rustix::fs::openat(
+            CWD,
+            root,
+            OFlags::DIRECTORY | OFlags::RDONLY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(FanotifyGroup {
+            fd,
+            mount,
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub fn watch(
+        self,
+        events: EventFilter,
+        handler: impl Fn(Vec<RefreshRequest>) + Send + 'static,
+    ) -> FanotifyWatcher {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            if let Err(e) = self.read_events(&events, &handler, &thread_stop) {
+                error!("Failed to read fanotify events, changes here aren't noticed: {e}");
+            }
+        });
+        FanotifyWatcher {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    fn read_events(
+        &self,
+        events: &EventFilter,
+        handler: &impl Fn(Vec<RefreshRequest>),
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        let mut buffer = vec![0; 64 * 1024];
+        while !stop.load(Ordering::Relaxed) {
+            let mut poll_fds = [PollFd::new(&self.fd, PollFlags::IN)];
+            match rustix::event::poll(&mut poll_fds, STOP_CHECK_MS) {
+                Ok(0) | Err(Errno::INTR) => continue,
+                Ok(_) => {}
+                Err(e) => return Err(e.into()),
+            }
+            let len = match rustix::io::read(&self.fd, &mut buffer) {
+                Ok(len) => len,
+                Err(Errno::AGAIN | Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mut requests = Vec::new();
+            for event in parse_events(&buffer[..len]) {
+                if event.mask & FAN_Q_OVERFLOW != 0 {
+                    // events were dropped, so anything may have changed
+                    requests.push(RefreshRequest::FullRescan(self.root.clone()));
+                    continue;
+                }
+                // the mark covers the whole filesystem, most of it isn't the root
+                let Some(dir) = self.open_dir(&event.handle) else {
+                    continue;
+                };
+                if !dir.starts_with(&self.root) {
+                    continue;
+                }
+                let path = dir.join(OsStr::from_bytes(&event.name));
+                let is_dir = event.mask & FAN_ONDIR != 0 && event.mask & ENTRY_EVENTS != 0;
+                requests.extend(events.requests([path], is_dir));
+            }
+            if !requests.is_empty() {
+                handler(requests);
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of the directory with file handle `handle`, unless it is gone.
+    fn open_dir(&self, handle: &[u8]) -> Option<PathBuf> {
+        // a `struct file_handle`, aligned like one
+        let mut aligned = vec![0u64; handle.len().div_ceil(8)];
+        // SAFETY: `aligned` has room for all of `handle`
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                handle.as_ptr(),
+                aligned.as_mut_ptr().cast::<u8>(),
+                handle.len(),
+            );
+        }
+        // SAFETY: `aligned` holds a file handle of the length its header gives
+        let fd = unsafe {
+            libc::open_by_handle_at(
+                self.mount.as_raw_fd(),
+                aligned.as_mut_ptr().cast(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        // SAFETY: open_by_handle_at just returned it, nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok()
+    }
+}
+
+impl Drop for FanotifyWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Splits a buffer read from a fanotify group into its events. Events without a
+/// directory handle, like an overflow of the queue, have an empty one.
+fn parse_events(buffer: &[u8]) -> Vec<RawEvent> {
+    let u16_at =
+        |bytes: &[u8], at: usize| u16::from_ne_bytes(bytes[at..at + 2].try_into().unwrap());
+    let u32_at =
+        |bytes: &[u8], at: usize| u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap());
+    let mut events = Vec::new();
+    let mut rest = buffer;
+    while rest.len() >= METADATA_LEN {
+        let event_len = u32_at(rest, 0) as usize;
+        let metadata_len = u16_at(rest, 6) as usize;
+        if event_len < METADATA_LEN || event_len > rest.len() || metadata_len > event_len {
+            error!("Malformed fanotify event");
+            break;
+        }
+        let mut event = RawEvent {
+            mask: u64::from_ne_bytes(rest[8..16].try_into().unwrap()),
+            handle: Vec::new(),
+            name: Vec::new(),
+        };
+        let mut info = &rest[metadata_len..event_len];
+        while info.len() >= INFO_HEADER_LEN {
+            let info_type = info[0];
+            let info_len = (u16_at(info, 2) as usize).min(info.len());
+            if info_len < INFO_HEADER_LEN {
+                break;
+            }
+            let record = &info[..info_len];
+            info = &info[info_len..];
+            if info_type != libc::FAN_EVENT_INFO_TYPE_DFID_NAME
+                || record.len() < FILE_HANDLE_OFFSET + FILE_HANDLE_HEADER_LEN
+            {
+                continue;
+            }
+            let handle_bytes = u32_at(record, FILE_HANDLE_OFFSET) as usize;
+            let name_offset = FILE_HANDLE_OFFSET + FILE_HANDLE_HEADER_LEN + handle_bytes;
+            let Some(name) = record.get(name_offset..) else {
+                continue;
+            };
+            event.handle = record[FILE_HANDLE_OFFSET..name_offset].to_vec();
+            event.name = name.split(|&b| b == 0).next().unwrap_or_default().to_vec();
+        }
+        events.push(event);
+        rest = &rest[event_len..];
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn test_parse_events() {
+        let mut buffer = Vec::new();
+        let handle: &[u8] = &[4, 0, 0, 0, 1, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0xdd];
+        let name = b"file.txt\0\0\0\0";
+        let info_len = FILE_HANDLE_OFFSET + handle.len() + name.len();
+        let event_len = (METADATA_LEN + info_len) as u32;
+        buffer.extend(event_len.to_ne_bytes());
+        buffer.extend([3, 0]);
+        buffer.extend((METADATA_LEN as u16).to_ne_bytes());
+        buffer.extend(FAN_CREATE.to_ne_bytes());
+        buffer.extend((-1i32).to_ne_bytes());
+        buffer.extend(0i32.to_ne_bytes());
+        buffer.extend([libc::FAN_EVENT_INFO_TYPE_DFID_NAME, 0]);
+        buffer.extend((info_len as u16).to_ne_bytes());
+        buffer.extend([0; 8]);
+        buffer.extend(handle);
+        buffer.extend(name);
+        // an overflow has no info records
+        buffer.extend((METADATA_LEN as u32).to_ne_bytes());
+        buffer.extend([3, 0]);
+        buffer.extend((METADATA_LEN as u16).to_ne_bytes());
+        buffer.extend(FAN_Q_OVERFLOW.to_ne_bytes());
+        buffer.extend([0; 8]);
+
+        let events = parse_events(&buffer);
+        assert_eq!(
+            events,
+            [
+                RawEvent {
+                    mask: FAN_CREATE,
+                    handle: handle.to_vec(),
+                    name: b"file.txt".to_vec(),
+                },
+                RawEvent {
+                    mask: FAN_Q_OVERFLOW,
+                    handle: Vec::new(),
+                    name: Vec::new(),
+                },
+            ]
+        );
+        // truncated
+        assert_eq!(parse_events(&buffer[..30]).len(), 0);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN for fanotify"]
+    fn test_fanotify_watcher() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let group = FanotifyGroup::new(&root).unwrap();
+        let events = EventFilter {
+            root: root.clone(),
+            cache_dir: root.join(crate::CACHE_DIR),
+            filter: Arc::default(),
+        };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let _watcher = group.watch(events, move |requests| {
+            let _ = tx.send(requests);
+        });
+        std::fs::write(root.join(".fync-tmp-1"), "ours").unwrap();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file"), "hello").unwrap();
+
+        let mut requests = Vec::new();
+        while let Ok(batch) = rx.recv_timeout(Duration::from_secs(2)) {
+            requests.extend(batch);
+            if requests.contains(&RefreshRequest::Path(root.join("dir/file"))) {
+                break;
+            }
+        }
+        assert!(requests.contains(&RefreshRequest::FullRescan(root.join("dir"))));
+        assert!(requests.contains(&RefreshRequest::Path(root.join("dir/file"))));
+        assert!(!requests.contains(&RefreshRequest::Path(root.join(".fync-tmp-1"))));
+    }
+}
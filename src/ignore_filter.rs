@@ -0,0 +1,267 @@
+//! Decides which paths are left out of syncing. The initial walk, rescans and watcher
+//! events all go through the same filter, so they agree on what is tracked.
+
+use anyhow::{Context, Result};
+use bincode::{Decode, Encode};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+use tracing::warn;
+
+use crate::{SyncOptions, CACHE_DIR};
+
+/// Files in a directory that hold ignore rules, by increasing precedence.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+/// Rules for fync only, in the root. Unlike the other ignore files, its `!` patterns can
+/// bring back hidden and git-ignored paths.
+pub(crate) const FYNCIGNORE_FILE: &str = ".fyncignore";
+
+/// Skips hidden files, and the paths matched by `.gitignore` and `.ignore` files in the
+/// root, `.git/info/exclude` and git's global excludes. Unlike git, `.gitignore` files
+/// apply outside of git repositories too. [`SyncRules`] take precedence over all of
+/// these.
+#[derive(Debug, Default)]
+pub struct IgnoreFilter {
+    rules: RwLock<Option<Gitignore>>,
+    global: OnceLock<Gitignore>,
+    /// Rules of each directory, by absolute path.
+    dirs: Mutex<HashMap<PathBuf, Arc<Vec<Gitignore>>>>,
+}
+
+/// What is synced on top of the ignore files in the tree. Both nodes must use the same
+/// rules, so they are exchanged when connecting.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct SyncRules {
+    /// Contents of `.fyncignore` in the root.
+    pub fyncignore: String,
+    /// Globs that are synced even if they are ignored otherwise.
+    pub include: Vec<String>,
+    /// Globs that are never synced.
+    pub exclude: Vec<String>,
+}
+
+impl SyncRules {
+    pub fn load(root: &Path, options: &SyncOptions) -> Result<Self> {
+        Ok(SyncRules {
+            fyncignore: read_fyncignore(root)?,
+            include: options.include.clone(),
+            exclude: options.exclude.clone(),
+        })
+    }
+
+    /// Later lines win, so `--include` beats `--exclude`, which beats `.fyncignore`.
+    fn build(&self) -> Result<Gitignore> {
+        let mut builder = GitignoreBuilder::new("");
+        let from = Some(PathBuf::from(FYNCIGNORE_FILE));
+        for line in self.fyncignore.lines() {
+            builder.add_line(from.clone(), line)?;
+        }
+        for glob in &self.exclude {
+            builder.add_line(None, glob)?;
+        }
+        for glob in &self.include {
+            builder.add_line(None, &format!("!{glob}"))?;
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn read_fyncignore(root: &Path) -> Result<String> {
+    match std::fs::read_to_string(root.join(FYNCIGNORE_FILE)) {
+        Ok(fyncignore) => Ok(fyncignore),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).context("Failed to read .fyncignore"),
+    }
+}
+
+pub(crate) fn is_ignore_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| IGNORE_FILES.iter().any(|f| name == *f))
+}
+
+impl IgnoreFilter {
+    pub fn set_rules(&self, rules: &SyncRules) -> Result<()> {
+        let rules = rules.build().context("Invalid sync rules")?;
+        *self.rules.write().unwrap() = Some(rules);
+        Ok(())
+    }
+
+    /// Whether `path` or any of its parents inside `root` is ignored.
+    pub fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        let mut current = root.to_path_buf();
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            current.push(component);
+            let is_last = components.peek().is_none();
+            if self.is_ignored_entry(root, &current, !is_last || is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether `path` itself is ignored, assuming its parents are not.
+    pub fn is_ignored_entry(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        if relative.as_os_str().is_empty() {
+            return false;
+        }
+        if relative.starts_with(CACHE_DIR) {
+            return true;
+        }
+        if let Some(rules) = &*self.rules.read().unwrap() {
+            match rules.matched_path_or_any_parents(relative, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        if path
+            .file_name()
+            .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+        {
+            return true;
+        }
+        // rules closer to the path win
+        for dir in path.ancestors().skip(1) {
+            for rules in self.dir_rules(root, dir).iter().rev() {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+            if dir == root {
+                break;
+            }
+        }
+        self.global().matched(relative, is_dir).is_ignore()
+    }
+
+    /// Forgets the rules of `dir`, after one of its ignore files changed.
+    pub fn invalidate(&self, dir: &Path) {
+        self.dirs.lock().unwrap().remove(dir);
+    }
+
+    /// Reads git's global excludes now instead of when they are first needed, e.g. before
+    /// a sandbox takes away access to them.
+    pub fn load_global_excludes(&self) {
+        self.global();
+    }
+
+    fn global(&self) -> &Gitignore {
+        self.global.get_or_init(|| {
+            let (global, error) = Gitignore::global();
+            if let Some(error) = error {
+                warn!("Failed to read global git excludes: {error}");
+            }
+            global
+        })
+    }
+
+    fn dir_rules(&self, root: &Path, dir: &Path) -> Arc<Vec<Gitignore>> {
+        if let Some(rules) = self.dirs.lock().unwrap().get(dir) {
+            return rules.clone();
+        }
+        let mut files: Vec<_> = IGNORE_FILES.iter().map(|f| dir.join(f)).collect();
+        if dir == root {
+            files.insert(0, dir.join(".git/info/exclude"));
+        }
+        let rules: Vec<_> = files
+            .into_iter()
+            .filter(|file| file.is_file())
+            .filter_map(|file| {
+                let mut builder = GitignoreBuilder::new(dir);
+                if let Some(error) = builder.add(&file) {
+                    warn!(?file, "Failed to parse ignore file: {error}");
+                }
+                builder
+                    .build()
+                    .inspect_err(|error| warn!(?file, "Failed to parse ignore file: {error}"))
+                    .ok()
+            })
+            .collect();
+        let rules = Arc::new(rules);
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), rules.clone());
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("web/node_modules")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n*.log\n").unwrap();
+        std::fs::write(root.join("web/.gitignore"), "node_modules\n!keep.log\n").unwrap();
+
+        let filter = IgnoreFilter::default();
+        assert!(filter.is_ignored(root, &root.join("target/debug/fync"), false));
+        assert!(filter.is_ignored(root, &root.join("web/node_modules/x.js"), false));
+        assert!(filter.is_ignored(root, &root.join("a.log"), false));
+        assert!(filter.is_ignored(root, &root.join(".git/HEAD"), false));
+        assert!(!filter.is_ignored(root, &root.join("web/keep.log"), false));
+        assert!(!filter.is_ignored(root, &root.join("web/index.js"), false));
+        // a file named like an ignored directory
+        assert!(!filter.is_ignored(root, &root.join("target"), false));
+
+        std::fs::write(root.join(".gitignore"), "").unwrap();
+        assert!(filter.is_ignored(root, &root.join("a.log"), false));
+        filter.invalidate(root);
+        assert!(!filter.is_ignored(root, &root.join("a.log"), false));
+    }
+
+    #[test]
+    fn test_sync_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join(".gitignore"), ".env.local\nbuild/\n").unwrap();
+
+        let filter = IgnoreFilter::default();
+        let rules = SyncRules {
+            fyncignore: "fixtures/*\n!.env.local\n!.github\n".into(),
+            include: vec!["fixtures/small.json".into()],
+            exclude: vec!["*.bin".into(), "fixtures/small.json".into()],
+        };
+        filter.set_rules(&rules).unwrap();
+        assert!(!filter.is_ignored(root, &root.join(".env.local"), false));
+        assert!(!filter.is_ignored(root, &root.join(".github/workflows/ci.yml"), false));
+        assert!(filter.is_ignored(root, &root.join("fixtures/big.json"), false));
+        assert!(!filter.is_ignored(root, &root.join("fixtures/small.json"), false));
+        assert!(filter.is_ignored(root, &root.join("data.bin"), false));
+        assert!(filter.is_ignored(root, &root.join("build/out"), false));
+        assert!(filter.is_ignored(root, &root.join(".fync/state"), false));
+
+        let mut options = SyncOptions::default();
+        options.include.push("*.env".into());
+        std::fs::write(root.join(".fyncignore"), "!.env.local\n").unwrap();
+        let rules = SyncRules::load(root, &options).unwrap();
+        assert_eq!(rules.fyncignore, "!.env.local\n");
+        assert_eq!(rules.include, vec!["*.env"]);
+
+        // an edited .fyncignore waits for the nodes to negotiate rules again
+        std::fs::write(root.join(".fyncignore"), "fixtures/*\n").unwrap();
+        assert!(!filter.is_ignored(root, &root.join(".env.local"), false));
+        assert!(!is_ignore_file(&root.join(".fyncignore")));
+    }
+}
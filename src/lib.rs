@@ -1,31 +1,88 @@
-// FIXME: protect against attacks
-// TODO: landlock support
-// TODO: think about atomically writting files
-
 use anyhow::{bail, Context, Result};
 use bincode::{Decode, Encode};
 use blake3::Hash as ContentHash;
+use blob_store::{read_file_at, BlobStore};
+pub use conflict::{Conflict, ConflictChoice, ConflictPolicy, MergeMode, Resolution};
+pub use control::{
+    handle_control_request, send_control_request, serve_control, ConflictVersion, ControlCall,
+    ControlRequest, ControlResponse,
+};
+use dedup::{chunk_content, ChunkInfo, ChunkPart, ChunkedContent, DedupIndex, MIN_DEDUP_BYTES};
+use fanotify_watcher::FanotifyGroup;
+use ignore_filter::{is_ignore_file, FYNCIGNORE_FILE};
+pub use ignore_filter::{IgnoreFilter, SyncRules};
+pub use limits::Limits;
 use notify_debouncer_full::notify::{
     self,
     event::{CreateKind, RemoveKind},
-    RecommendedWatcher, RecursiveMode, Watcher,
+    PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+pub use protocol::{Features, Hello};
+use safe_fs::Entry;
+pub use sandbox::sandbox_to_root;
+use state_cache::{
+    load_conflicts, load_or_create_node_id, load_peer_states, save_conflicts, save_peer_state,
+    validate_node_id, StateCache,
 };
 use std::{
-    collections::{btree_map, BTreeMap, HashMap, HashSet},
-    io::ErrorKind,
-    path::{Path, PathBuf},
+    borrow::Cow,
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use transfer::{Download, PartialContent, Upload, CHUNKED_CONTENT_BYTES};
+pub use wire::{decode_message, FrameReader, FrameWriter};
+
+mod blob_store;
+mod conflict;
+mod control;
+mod dedup;
+mod fanotify_watcher;
+mod ignore_filter;
+mod limits;
+mod protocol;
+mod safe_fs;
+mod sandbox;
+mod state_cache;
+mod transfer;
+mod wire;
+
+/// Directory inside the root where fync keeps its own data. It is never synced.
+pub const CACHE_DIR: &str = ".fync";
+
+fn cache_dir(root: &Path) -> PathBuf {
+    root.join(CACHE_DIR)
+}
 
 /// A relative path to some root.
 #[derive(Debug, Eq, PartialOrd, Ord, PartialEq, Clone, Encode, Decode)]
 pub struct FilePath(Arc<str>);
 
+/// Permission bits that are synced. setuid/setgid/sticky are deliberately left out.
+const PERMISSION_BITS: u32 = 0o777;
+/// Mode of every file when [`Features::MODES`] is off.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+/// Larger contents are compressed on their own instead of as a delta against the old
+/// version, which needs both versions in memory and is slow to build.
+const MAX_DELTA_BYTES: usize = 32 * 1024 * 1024;
+
 #[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
-pub struct FileMetadata {
-    #[bincode(with_serde)]
-    content_hash: ContentHash,
+pub enum FileMetadata {
+    File {
+        #[bincode(with_serde)]
+        content_hash: ContentHash,
+        /// Unix permission bits (masked with `PERMISSION_BITS`).
+        mode: u32,
+    },
+    Symlink {
+        /// Link target, as returned by `readlink`.
+        target: Arc<str>,
+    },
+    Directory,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
@@ -33,6 +90,168 @@ pub struct FsState {
     files: BTreeMap<FilePath, FileMetadata>,
 }
 
+/// Which symlinks are allowed to be synced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Sync every symlink as is.
+    #[default]
+    Allow,
+    /// Refuse symlinks with an absolute target.
+    RejectAbsolute,
+    /// Refuse symlinks whose target is absolute or leaves the root.
+    WithinRoot,
+}
+
+/// How changes to the root are noticed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WatcherKind {
+    /// One fanotify mark on the filesystem of the root, nothing to set up per
+    /// directory. Needs `CAP_SYS_ADMIN`, inotify is used without it.
+    Fanotify,
+    /// An inotify watch on every directory.
+    #[default]
+    Inotify,
+    /// Scans the root every few seconds, for filesystems without change events.
+    Poll,
+}
+
+/// Settings that control how a node reads and writes its root.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub symlink_policy: SymlinkPolicy,
+    /// fsync files before renaming them into place.
+    pub fsync: bool,
+    /// Globs synced even if ignored otherwise, see [`SyncRules`].
+    pub include: Vec<String>,
+    /// Globs never synced, see [`SyncRules`].
+    pub exclude: Vec<String>,
+    pub ignore_filter: Arc<IgnoreFilter>,
+    /// Negotiated with the other node, see [`Hello`].
+    pub features: Features,
+    /// How changes of the other node that conflict with local ones are resolved.
+    pub conflict_policy: ConflictPolicy,
+    /// Whether text files edited on both sides are merged before `conflict_policy` applies.
+    pub merge: MergeMode,
+    /// Bounds on what the other node can send, see [`Limits`].
+    pub limits: Limits,
+    /// How changes in the root are noticed, see [`watch_root`].
+    pub watcher: WatcherKind,
+}
+
+/// Files are written to a temporary file next to the target, and then renamed over it.
+const TEMP_FILE_PREFIX: &str = ".fync-tmp-";
+
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        name.as_encoded_bytes()
+            .starts_with(TEMP_FILE_PREFIX.as_bytes())
+    })
+}
+
+fn write_file_atomically(path: &Path, content: &[u8], mode: u32, fsync: bool) -> Result<()> {
+    let parent = path.parent().context("File without a parent directory")?;
+    let mut file = tempfile::Builder::new()
+        .prefix(TEMP_FILE_PREFIX)
+        .tempfile_in(parent)?;
+    file.write_all(content)?;
+    file.as_file()
+        .set_permissions(std::fs::Permissions::from_mode(mode))?;
+    if fsync {
+        file.as_file().sync_all()?;
+    }
+    file.persist(path)?;
+    Ok(())
+}
+
+impl SymlinkPolicy {
+    fn allows(self, link: &FilePath, target: &str) -> bool {
+        let target = Path::new(target);
+        match self {
+            SymlinkPolicy::Allow => true,
+            SymlinkPolicy::RejectAbsolute => !target.is_absolute(),
+            SymlinkPolicy::WithinRoot => {
+                // resolved lexically, relative to the directory containing the link
+                let mut depth = Path::new(link.0.as_ref()).components().count() as isize - 1;
+                for component in target.components() {
+                    match component {
+                        Component::Normal(_) => depth += 1,
+                        Component::CurDir => {}
+                        Component::ParentDir => {
+                            depth -= 1;
+                            if depth < 0 {
+                                return false;
+                            }
+                        }
+                        Component::RootDir | Component::Prefix(_) => return false,
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+impl SyncOptions {
+    fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        self.ignore_filter.is_ignored(root, path, is_dir)
+    }
+
+    /// Walks `dir` inside `root`, skipping ignored paths.
+    fn walk(&self, root: &Path, dir: &Path) -> ignore::Walk {
+        let filter = self.ignore_filter.clone();
+        let root = root.to_path_buf();
+        ignore::WalkBuilder::new(dir)
+            .standard_filters(false)
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                !filter.is_ignored_entry(&root, entry.path(), is_dir)
+            })
+            .build()
+    }
+
+    fn allows(&self, file_path: &FilePath, meta: &FileMetadata) -> bool {
+        match meta {
+            FileMetadata::File { .. } | FileMetadata::Directory => true,
+            FileMetadata::Symlink { target } => {
+                if !self.features.contains(Features::SYMLINKS) {
+                    warn!(
+                        ?file_path,
+                        "Symlink skipped, the other node doesn't sync symlinks"
+                    );
+                    return false;
+                }
+                let allowed = self.symlink_policy.allows(file_path, target);
+                if !allowed {
+                    warn!(?file_path, ?target, "Symlink rejected by symlink policy");
+                }
+                allowed
+            }
+        }
+    }
+}
+
+/// Like `Path::exists`, but doesn't follow symlinks.
+fn path_exists(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+/// Reads a zstd stream from the other node. The buffer grows with what is decoded, so a
+/// small frame claiming a large size can't make this node allocate more than `limit`.
+fn decompress_bounded(decoder: impl Read, limit: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    decoder.take(limit as u64 + 1).read_to_end(&mut data)?;
+    if data.len() > limit {
+        bail!("Decompressed content longer than {limit} bytes");
+    }
+    Ok(data)
+}
+
+impl std::fmt::Display for FilePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 impl FilePath {
     fn from_root_and_path(path: &Path, root: &Path) -> Result<FilePath> {
         Ok(FilePath(Arc::from(
@@ -43,30 +262,188 @@ impl FilePath {
     fn to_absolute(&self, root: &Path) -> PathBuf {
         root.join(self.0.as_ref())
     }
+
+    /// Checks a path from the other node: relative, made of plain names, and outside the
+    /// cache directory.
+    fn validate(&self) -> Result<()> {
+        let mut components = self.0.split('/');
+        let valid = !self.0.contains('\0')
+            && components.clone().next() != Some(CACHE_DIR)
+            && components.all(|component| !matches!(component, "" | "." | ".."));
+        if !valid {
+            bail!("Invalid path from the other node: {self:?}");
+        }
+        Ok(())
+    }
+}
+
+/// The parts of `stat` that tell whether a file changed since it was last hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+struct FileStat {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    inode: u64,
+}
+
+impl FileStat {
+    fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        FileStat {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            inode: metadata.ino(),
+        }
+    }
+
+    fn mtime_ns(&self) -> i64 {
+        self.mtime * 1_000_000_000 + self.mtime_nsec
+    }
 }
 
 impl FileMetadata {
-    fn from_fs(file: &Path, content_store: &mut ContentStore) -> Result<Option<Self>> {
-        match std::fs::read(file) {
-            Ok(content) => {
-                let content_hash = content_store.add(content);
-                Ok(Some(FileMetadata { content_hash }))
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    fn from_fs(
+        path: &Path,
+        options: &SyncOptions,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<Self>> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            return match std::fs::read_link(path) {
+                Ok(target) => Ok(Some(FileMetadata::Symlink {
+                    target: Arc::from(target.to_string_lossy().as_ref()),
+                })),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            };
+        }
+        if file_type.is_dir() {
+            return Ok(Some(FileMetadata::Directory));
+        }
+        if !file_type.is_file() {
+            bail!("Unsupported file type at {}", path.display());
+        }
+        let mode = if options.features.contains(Features::MODES) {
+            metadata.permissions().mode() & PERMISSION_BITS
+        } else {
+            DEFAULT_FILE_MODE
+        };
+        // taken before reading, so a write during the read is picked up next time
+        let stat = FileStat::from_metadata(&metadata);
+        if let Some(content_hash) = content_store.known_file_hash(path, &stat) {
+            return Ok(Some(FileMetadata::File { content_hash, mode }));
+        }
+        let mut file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let content_hash = content_store.add(content)?;
+        content_store.record_file(path.to_path_buf(), stat, content_hash);
+        Ok(Some(FileMetadata::File { content_hash, mode }))
+    }
+
+    fn content_hash(&self) -> Option<ContentHash> {
+        match self {
+            FileMetadata::File { content_hash, .. } => Some(*content_hash),
+            FileMetadata::Symlink { .. } | FileMetadata::Directory => None,
+        }
+    }
+
+    /// Removes `entry`, returns false if it is a directory that isn't empty.
+    fn remove_from_disk(&self, entry: &Entry) -> Result<bool> {
+        match entry.remove(*self == FileMetadata::Directory) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+            Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => Ok(false),
             Err(e) => Err(e.into()),
         }
     }
+
+    /// Makes `file_path` match `self`, given that it currently matches `current`.
+    /// Returns false if the current entry is a directory that isn't empty.
+    fn write_to_disk(
+        &self,
+        root: &Path,
+        file_path: &FilePath,
+        current: Option<&FileMetadata>,
+        options: &SyncOptions,
+        content_store: &ContentStore,
+    ) -> Result<bool> {
+        if current == Some(self) {
+            return Ok(true);
+        }
+        let entry = safe_fs::open_entry(root, file_path, true)
+            .with_context(|| format!("Failed to open the directory of {file_path:?}"))?;
+        // an old link isn't written through, and entries of other kinds are replaced,
+        // while a file is overwritten in place
+        if let Some(current) = current {
+            let in_place = matches!(
+                (current, self),
+                (FileMetadata::File { .. }, FileMetadata::File { .. })
+            );
+            if !in_place && !current.remove_from_disk(&entry)? {
+                return Ok(false);
+            }
+        }
+        match self {
+            FileMetadata::File { content_hash, mode } => {
+                let same_content = matches!(
+                    current,
+                    Some(FileMetadata::File { content_hash: current_hash, .. })
+                        if current_hash == content_hash
+                );
+                let sync_modes = options.features.contains(Features::MODES);
+                if same_content {
+                    if sync_modes {
+                        entry.set_mode(*mode)?;
+                    }
+                } else {
+                    // without synced modes, a file keeps its mode when it is overwritten
+                    let mode = match entry.file_mode() {
+                        Some(current_mode) if !sync_modes => current_mode,
+                        _ => *mode,
+                    };
+                    let content = content_store.get(content_hash)?;
+                    entry.write_file(&content, mode, options.fsync)?;
+                }
+            }
+            FileMetadata::Symlink { target } => entry.symlink(target)?,
+            FileMetadata::Directory => match entry.create_dir() {
+                Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
+                _ => {}
+            },
+        }
+        Ok(true)
+    }
 }
 
 impl FsState {
-    pub fn from_disk(root: &Path, content_store: &mut ContentStore) -> Result<Self> {
+    pub fn from_disk(
+        root: &Path,
+        options: &SyncOptions,
+        content_store: &mut ContentStore,
+    ) -> Result<Self> {
         let mut files = BTreeMap::new();
-        for entry in ignore::Walk::new(root) {
+        // skip the root itself
+        for entry in options.walk(root, root).skip(1) {
             let entry = entry?;
-            if entry.file_type().map_or(false, |d| d.is_file()) {
+            if entry
+                .file_type()
+                .is_some_and(|d| d.is_file() || d.is_symlink() || d.is_dir())
+            {
                 let file_path = FilePath::from_root_and_path(entry.path(), root)?;
-                if let Some(meta) = FileMetadata::from_fs(entry.path(), content_store)? {
-                    files.insert(file_path, meta);
+                if let Some(meta) = FileMetadata::from_fs(entry.path(), options, content_store)? {
+                    if options.allows(&file_path, &meta) {
+                        files.insert(file_path, meta);
+                    }
                 }
             }
         }
@@ -77,15 +454,30 @@ impl FsState {
         &mut self,
         root: &Path,
         path: &Path,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
     ) -> Result<Option<(FilePath, FileChange)>> {
         let file_path = FilePath::from_root_and_path(path, root)?;
+        if file_path.0.is_empty() {
+            return Ok(None);
+        }
 
-        let new_metadata = FileMetadata::from_fs(path, content_store);
-        if let Ok(Some(new_metadata)) = new_metadata {
+        let new_metadata = match FileMetadata::from_fs(path, options, content_store) {
+            Ok(Some(meta))
+                if !options.allows(&file_path, &meta)
+                    || options.is_ignored(root, path, meta == FileMetadata::Directory) =>
+            {
+                None
+            }
+            Ok(meta) => meta,
+            // noop for other file types
+            Err(_) if path_exists(path) => return Ok(None),
+            Err(_) => None,
+        };
+        if let Some(new_metadata) = new_metadata {
             match self.files.entry(file_path.clone()) {
                 btree_map::Entry::Occupied(mut entry) => {
-                    if entry.get().content_hash != new_metadata.content_hash {
+                    if *entry.get() != new_metadata {
                         let old_metadata = entry.insert(new_metadata.clone());
                         Ok(Some((
                             file_path,
@@ -106,19 +498,14 @@ impl FsState {
                     )))
                 }
             }
-        } else if !path.exists() {
-            if let Some(old_metadata) = self.files.remove(&file_path) {
-                Ok(Some((
-                    file_path,
-                    FileChange::Removed {
-                        old_meta: old_metadata,
-                    },
-                )))
-            } else {
-                Ok(None)
-            }
+        } else if let Some(old_metadata) = self.files.remove(&file_path) {
+            Ok(Some((
+                file_path,
+                FileChange::Removed {
+                    old_meta: old_metadata,
+                },
+            )))
         } else {
-            // noop for other file types
             Ok(None)
         }
     }
@@ -126,6 +513,7 @@ impl FsState {
         &mut self,
         root: &Path,
         directory: &Path,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
     ) -> Result<FsStateDiff> {
         let mut diff = FsStateDiff {
@@ -134,12 +522,16 @@ impl FsState {
         let full_dir_path = root.join(directory);
         let dir_prefix = FilePath::from_root_and_path(&full_dir_path, root)?;
 
-        // Remove entries that no longer exist
+        // Remove entries that no longer exist, or are ignored now
         let removed: Vec<_> = self
             .files
             .range(dir_prefix.clone()..)
             .take_while(|(k, _)| k.0.starts_with(&*dir_prefix.0))
-            .filter(|(k, _)| !k.to_absolute(root).exists())
+            .filter(|(k, v)| {
+                let path = k.to_absolute(root);
+                !path_exists(&path)
+                    || options.is_ignored(root, &path, **v == FileMetadata::Directory)
+            })
             .map(|(k, v)| (k.clone(), v.clone()))
             .collect();
 
@@ -150,13 +542,16 @@ impl FsState {
         }
 
         // Walk the directory and update/add entries
-        for entry in ignore::Walk::new(&full_dir_path) {
+        for entry in options.walk(root, &full_dir_path) {
             let Ok(entry) = entry else {
                 continue;
             };
-            if entry.file_type().map_or(false, |d| d.is_file()) {
+            if entry
+                .file_type()
+                .is_some_and(|d| d.is_file() || d.is_symlink() || d.is_dir())
+            {
                 if let Some((file_path, change)) =
-                    self.refresh_path(root, entry.path(), content_store)?
+                    self.refresh_path(root, entry.path(), options, content_store)?
                 {
                     diff.files.insert(file_path, change);
                 }
@@ -171,7 +566,7 @@ impl FsState {
         for (file_name, metadata) in &self.files {
             match next.files.get(file_name) {
                 Some(other_metadata) => {
-                    if metadata.content_hash != other_metadata.content_hash {
+                    if metadata != other_metadata {
                         files.insert(
                             file_name.clone(),
                             FileChange::Modified {
@@ -211,7 +606,10 @@ impl FsState {
         let mut conflicts = Vec::new();
         for (file_path, change) in &diff.files {
             let current_status = self.files.get(file_path);
-            if change.conflicts(current_status) {
+            let source_status = change.renamed_from().map(|from| self.files.get(from));
+            if change.conflicts(current_status)
+                || source_status.is_some_and(|source| change.source_conflicts(source))
+            {
                 conflicts.push(file_path.clone());
             } else {
                 match change {
@@ -221,6 +619,10 @@ impl FsState {
                     FileChange::Created { meta } | FileChange::Modified { new_meta: meta, .. } => {
                         self.files.insert(file_path.clone(), meta.clone());
                     }
+                    FileChange::Renamed { from, meta } => {
+                        self.files.remove(from);
+                        self.files.insert(file_path.clone(), meta.clone());
+                    }
                 }
             }
         }
@@ -228,49 +630,254 @@ impl FsState {
         conflicts
     }
 
+    /// Sets the paths of `diff` to what they are after it, whatever they were before. The
+    /// changes of the other node say what it has now, even where the state kept of it
+    /// is behind, e.g. after a conflict.
+    fn set_from_diff(&mut self, diff: &FsStateDiff) {
+        for (file_path, change) in &diff.files {
+            if let Some(from) = change.renamed_from() {
+                self.files.remove(from);
+            }
+            match change.new_meta() {
+                Some(meta) => self.files.insert(file_path.clone(), meta.clone()),
+                None => self.files.remove(file_path),
+            };
+        }
+    }
+
+    /// Returns the changes that weren't applied, and why.
     pub fn apply_diff_to_disk(
         &mut self,
         diff: &FsStateDiff,
         root: &Path,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
-    ) -> Result<Vec<FilePath>> {
-        let mut conflicts = Vec::new();
-        for (file_path, change) in &diff.files {
-            let full_path = file_path.to_absolute(root);
-            let metadata = FileMetadata::from_fs(&full_path, content_store)?;
-            if change.conflicts(metadata.as_ref()) {
-                conflicts.push(file_path.clone());
-                continue;
+    ) -> Result<BTreeMap<FilePath, Outcome>> {
+        let mut not_applied = BTreeMap::new();
+        // directories can only go away once everything inside them is gone,
+        // so they are handled last, deepest first.
+        let (dir_removals, changes): (Vec<_>, Vec<_>) =
+            diff.files.iter().partition(|(_, change)| {
+                matches!(
+                    change,
+                    FileChange::Removed {
+                        old_meta: FileMetadata::Directory
+                    } | FileChange::Modified {
+                        old_meta: FileMetadata::Directory,
+                        ..
+                    }
+                )
+            });
+        for (file_path, change) in changes.into_iter().chain(dir_removals.into_iter().rev()) {
+            match self.apply_change_to_disk(file_path, change, root, options, content_store)? {
+                Outcome::Applied => {}
+                outcome => {
+                    not_applied.insert(file_path.clone(), outcome);
+                }
             }
-            match change {
-                FileChange::Removed { .. } => {
-                    if let Err(e) = std::fs::remove_file(&full_path) {
-                        if e.kind() != ErrorKind::NotFound {
-                            return Err(e.into());
-                        }
+        }
+        Ok(not_applied)
+    }
+
+    fn apply_change_to_disk(
+        &mut self,
+        file_path: &FilePath,
+        change: &FileChange,
+        root: &Path,
+        options: &SyncOptions,
+        content_store: &mut ContentStore,
+    ) -> Result<Outcome> {
+        let full_path = file_path.to_absolute(root);
+        let is_dir = match change {
+            FileChange::Removed { old_meta: meta } => *meta == FileMetadata::Directory,
+            FileChange::Created { meta }
+            | FileChange::Modified { new_meta: meta, .. }
+            | FileChange::Renamed { meta, .. } => *meta == FileMetadata::Directory,
+        };
+        if options.is_ignored(root, &full_path, is_dir) {
+            // not tracked here, so there is nothing to remove
+            if matches!(change, FileChange::Removed { .. }) {
+                return Ok(Outcome::Applied);
+            }
+            info!(path = ?file_path, "Change skipped, the path is ignored here");
+            return Ok(Outcome::NotTracked);
+        }
+        let source = change.renamed_from();
+        if let Some(path) = [Some(file_path), source]
+            .into_iter()
+            .flatten()
+            .find(|path| !safe_fs::is_reachable(root, path))
+        {
+            warn!(
+                ?path,
+                "Not following a symlink or file in the way of a change"
+            );
+            return Ok(Outcome::Conflict);
+        }
+        if change
+            .new_meta()
+            .is_some_and(|meta| !options.allows(file_path, meta))
+        {
+            return Ok(Outcome::NotTracked);
+        }
+        let metadata = FileMetadata::from_fs(&full_path, options, content_store)?;
+        if change.conflicts(metadata.as_ref()) {
+            return Ok(Outcome::Conflict);
+        }
+        match change {
+            FileChange::Removed { .. } => {
+                if let Some(current) = &metadata {
+                    let entry = safe_fs::open_entry(root, file_path, false)?;
+                    if !current.remove_from_disk(&entry)? {
+                        return Ok(Outcome::Conflict);
                     }
-                    self.files.remove(file_path);
                 }
-                FileChange::Created { meta } | FileChange::Modified { new_meta: meta, .. } => {
-                    if let Some(parent) = full_path.parent() {
-                        std::fs::create_dir_all(parent)?;
+                self.files.remove(file_path);
+                self.prune_empty_parents(file_path, root)?;
+            }
+            FileChange::Created { meta } | FileChange::Modified { new_meta: meta, .. } => {
+                if !meta.write_to_disk(
+                    root,
+                    file_path,
+                    metadata.as_ref(),
+                    options,
+                    content_store,
+                )? {
+                    return Ok(Outcome::Conflict);
+                }
+                self.files.insert(file_path.clone(), meta.clone());
+            }
+            FileChange::Renamed { from, meta } => {
+                let from_path = from.to_absolute(root);
+                let source = if options.is_ignored(root, &from_path, false) {
+                    None
+                } else {
+                    FileMetadata::from_fs(&from_path, options, content_store)?
+                };
+                if change.source_conflicts(source.as_ref()) {
+                    return Ok(Outcome::Conflict);
+                }
+                let moved = match (&metadata, &source) {
+                    (None, Some(_)) => {
+                        let to = safe_fs::open_entry(root, file_path, true)?;
+                        let renamed = safe_fs::open_entry(root, from, false)
+                            .and_then(|from| from.rename_to(&to));
+                        match renamed {
+                            Ok(()) => {
+                                content_store.record_rename(&from_path, full_path);
+                                true
+                            }
+                            // the source went away since it was looked at
+                            Err(e) if e.kind() == ErrorKind::NotFound => false,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    // already here, only the source is left
+                    (Some(_), Some(source)) => {
+                        if !source.remove_from_disk(&safe_fs::open_entry(root, from, false)?)? {
+                            return Ok(Outcome::Conflict);
+                        }
+                        true
                     }
-                    let content = content_store.get(&meta.content_hash)?;
-                    std::fs::write(&full_path, content)?;
-                    self.files.insert(file_path.clone(), meta.clone());
+                    (_, None) => false,
+                };
+                if !moved
+                    && !meta.write_to_disk(
+                        root,
+                        file_path,
+                        metadata.as_ref(),
+                        options,
+                        content_store,
+                    )?
+                {
+                    return Ok(Outcome::Conflict);
+                }
+                self.files.remove(from);
+                self.files.insert(file_path.clone(), meta.clone());
+                self.prune_empty_parents(from, root)?;
+            }
+        }
+        Ok(Outcome::Applied)
+    }
+
+    /// Applies `change` whatever is at its path now, so the other node's version replaces
+    /// the local one. Returns false if it could not be applied.
+    fn force_change_to_disk(
+        &mut self,
+        file_path: &FilePath,
+        change: &FileChange,
+        root: &Path,
+        options: &SyncOptions,
+        content_store: &mut ContentStore,
+    ) -> Result<bool> {
+        let mut changes = vec![(file_path, change.new_meta())];
+        if let Some(from) = change.renamed_from() {
+            changes.push((from, None));
+        }
+        for (file_path, new_meta) in changes {
+            let full_path = file_path.to_absolute(root);
+            let current = FileMetadata::from_fs(&full_path, options, content_store)?;
+            if let Some(change) = FileChange::between(current.as_ref(), new_meta) {
+                let outcome =
+                    self.apply_change_to_disk(file_path, &change, root, options, content_store)?;
+                if outcome != Outcome::Applied {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes parent directories of `file_path` that are left empty, unless they are
+    /// tracked themselves.
+    fn prune_empty_parents(&self, file_path: &FilePath, root: &Path) -> Result<()> {
+        let mut parent = Path::new(file_path.0.as_ref()).parent();
+        while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty()) {
+            let dir_path = FilePath(Arc::from(dir.to_string_lossy().as_ref()));
+            if self.files.contains_key(&dir_path) {
+                break;
+            }
+            let removed =
+                safe_fs::open_entry(root, &dir_path, false).and_then(|entry| entry.remove(true));
+            match removed {
+                Ok(()) => {}
+                Err(e)
+                    if matches!(e.kind(), ErrorKind::DirectoryNotEmpty | ErrorKind::NotFound) =>
+                {
+                    break
                 }
+                Err(e) => return Err(e.into()),
             }
+            parent = dir.parent();
         }
-        Ok(conflicts)
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    pub removed_blobs: usize,
+    pub remaining_blobs: usize,
+    /// Files on disk whose contents can be read back lazily.
+    pub files: usize,
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct FsStateDiff {
     // TODO: avoid sending same file path again and again
     pub files: BTreeMap<FilePath, FileChange>,
 }
 
+/// What became of a change of the other node applied to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    /// The local version changed too, and was left as it is.
+    Conflict,
+    /// The path isn't tracked here: it is ignored, or a symlink that isn't allowed.
+    NotTracked,
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
 pub enum FileChange {
     Removed {
@@ -283,191 +890,869 @@ pub enum FileChange {
         old_meta: FileMetadata,
         new_meta: FileMetadata,
     },
+    /// The file at `from` moved here, unchanged.
+    Renamed {
+        from: FilePath,
+        meta: FileMetadata,
+    },
 }
 
 impl FileChange {
+    /// Whether the change conflicts with what is at its path. A rename also checks its
+    /// source, see [`FileChange::source_conflicts`].
     fn conflicts(&self, current_metadata: Option<&FileMetadata>) -> bool {
         match (current_metadata, self) {
-            (Some(current_meta), FileChange::Removed { old_meta }) => {
-                current_meta.content_hash != old_meta.content_hash
-            }
+            (Some(current_meta), FileChange::Removed { old_meta }) => current_meta != old_meta,
             (None, FileChange::Removed { .. }) => false,
 
             (None, FileChange::Created { .. }) => false,
-            (Some(current_meta), FileChange::Created { meta }) => {
-                current_meta.content_hash != meta.content_hash
-            }
+            (Some(current_meta), FileChange::Created { meta }) => current_meta != meta,
 
             (Some(current_meta), FileChange::Modified { old_meta, new_meta }) => {
                 // Check if the current state differs from both old and new states
-                current_meta.content_hash != old_meta.content_hash
-                    && current_meta.content_hash != new_meta.content_hash
+                current_meta != old_meta && current_meta != new_meta
             }
             (None, FileChange::Modified { .. }) => true,
+
+            (None, FileChange::Renamed { .. }) => false,
+            (Some(current_meta), FileChange::Renamed { meta, .. }) => current_meta != meta,
         }
     }
-}
 
-impl FsStateDiff {
-    pub fn is_empty(&self) -> bool {
-        self.files.is_empty()
+    /// Whether a rename conflicts with what is at its source. A source that is already
+    /// gone is fine, the file is then written from its content.
+    fn source_conflicts(&self, current_source: Option<&FileMetadata>) -> bool {
+        match self {
+            FileChange::Renamed { meta, .. } => current_source.is_some_and(|source| source != meta),
+            _ => false,
+        }
     }
-}
 
-#[derive(Default, Debug)]
-pub struct ContentStore {
-    // todo: use better hash map
-    contents: HashMap<ContentHash, Vec<u8>>,
-    new_contents: HashSet<ContentHash>,
+    fn renamed_from(&self) -> Option<&FilePath> {
+        match self {
+            FileChange::Renamed { from, .. } => Some(from),
+            _ => None,
+        }
+    }
 }
 
-impl ContentStore {
-    pub fn add(&mut self, content: Vec<u8>) -> ContentHash {
-        let hash = blake3::hash(&content);
-        self.insert(hash, content);
-        hash
+impl FileChange {
+    fn content_hashes(&self) -> impl Iterator<Item = ContentHash> + '_ {
+        let (old_meta, new_meta) = match self {
+            FileChange::Removed { old_meta } => (Some(old_meta), None),
+            FileChange::Created { meta } | FileChange::Renamed { meta, .. } => (None, Some(meta)),
+            FileChange::Modified { old_meta, new_meta } => (Some(old_meta), Some(new_meta)),
+        };
+        [old_meta, new_meta]
+            .into_iter()
+            .flatten()
+            .filter_map(FileMetadata::content_hash)
     }
 
-    pub fn insert(&mut self, hash: ContentHash, content: Vec<u8>) {
-        if self.contents.insert(hash, content).is_none() {
-            self.new_contents.insert(hash);
+    fn old_meta(&self) -> Option<&FileMetadata> {
+        match self {
+            FileChange::Removed { old_meta } | FileChange::Modified { old_meta, .. } => {
+                Some(old_meta)
+            }
+            FileChange::Created { .. } | FileChange::Renamed { .. } => None,
         }
     }
 
-    pub fn get(&self, hash: &ContentHash) -> Result<&[u8]> {
-        self.contents
-            .get(hash)
-            .context("Content not found in content store")
-            .map(|x| x.as_slice())
+    fn new_meta(&self) -> Option<&FileMetadata> {
+        match self {
+            FileChange::Removed { .. } => None,
+            FileChange::Created { meta }
+            | FileChange::Modified { new_meta: meta, .. }
+            | FileChange::Renamed { meta, .. } => Some(meta),
+        }
     }
 
-    pub fn remove(&mut self, hash: &ContentHash) {
-        self.contents.remove(hash);
+    /// Hash of the content this change writes.
+    fn new_content_hash(&self) -> Option<ContentHash> {
+        self.new_meta().and_then(FileMetadata::content_hash)
     }
 
-    pub fn has(&self, hash: &ContentHash) -> bool {
-        self.contents.contains_key(hash)
+    fn between(old: Option<&FileMetadata>, new: Option<&FileMetadata>) -> Option<FileChange> {
+        match (old, new) {
+            (Some(old), Some(new)) if old == new => None,
+            (Some(old), Some(new)) => Some(FileChange::Modified {
+                old_meta: old.clone(),
+                new_meta: new.clone(),
+            }),
+            (Some(old), None) => Some(FileChange::Removed {
+                old_meta: old.clone(),
+            }),
+            (None, Some(new)) => Some(FileChange::Created { meta: new.clone() }),
+            (None, None) => None,
+        }
     }
+}
 
-    pub fn seen_from_other(&mut self, hash: &ContentHash) {
-        self.new_contents.remove(hash);
+impl FsStateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
     }
 
-    pub fn create_content_diff(&mut self, diff: &FsStateDiff) -> Result<ContentDiff> {
-        let mut content_diff = ContentDiff::new();
-
-        for (_, change) in &diff.files {
-            match change {
-                FileChange::Created { meta } => {
-                    if self.new_contents.remove(&meta.content_hash) {
-                        let content = self.get(&meta.content_hash)?;
-                        content_diff.add_new_content(content.to_vec());
-                    }
-                }
-                FileChange::Modified { old_meta, new_meta } => {
-                    let new_content_is_new = self.new_contents.remove(&new_meta.content_hash);
-                    let old_content_is_new = self.new_contents.remove(&old_meta.content_hash);
-                    let old_content = self.get(&old_meta.content_hash).ok();
-                    if let Some(old_content) = old_content {
-                        if old_content_is_new {
-                            content_diff.add_new_content(old_content.to_vec());
-                        }
-                    }
-
-                    if new_content_is_new {
-                        let new_content = self.get(&new_meta.content_hash)?;
-                        if let Some(old_content) = old_content {
-                            content_diff.add_modified_content(
-                                old_meta.content_hash.clone(),
-                                &old_content,
-                                &new_content,
-                            );
-                        } else {
-                            content_diff.add_new_content(new_content.to_vec());
-                        }
-                    }
-                }
-                FileChange::Removed { old_meta } => {
-                    if self.new_contents.remove(&old_meta.content_hash) {
-                        if let Some(old_content) = self.get(&old_meta.content_hash).ok() {
-                            content_diff.add_new_content(old_content.to_vec());
-                        }
-                    }
-                }
+    /// Rejects paths of the other node that could lead outside the root.
+    fn validate_paths(&self) -> Result<()> {
+        for (file_path, change) in &self.files {
+            file_path.validate()?;
+            if let Some(from) = change.renamed_from() {
+                from.validate()?;
             }
         }
-
-        Ok(content_diff)
+        Ok(())
     }
 
-    pub fn apply_content_diff_from_other(&mut self, content_diff: &ContentDiff) -> Result<()> {
-        for content in &content_diff.new_content {
-            let hash = blake3::hash(content);
-            self.contents.insert(hash, content.clone());
+    /// Turns a removed file and a created one with the same content and mode into a
+    /// rename, so the other node moves the file instead of writing it again. A file
+    /// that kept its name, as when its directory moved, is preferred.
+    fn detect_renames(&mut self) {
+        let mut removed: HashMap<ContentHash, Vec<&FilePath>> = HashMap::new();
+        for (file_path, change) in &self.files {
+            if let FileChange::Removed { old_meta } = change {
+                if let Some(hash) = old_meta.content_hash() {
+                    removed.entry(hash).or_default().push(file_path);
+                }
+            }
         }
-
-        for compressed_diff in &content_diff.modified_content {
-            let old_content = self.get(&compressed_diff.old_hash)?;
-            // 100MB
-            const MAX_BYTES: usize = 1024 * 1024 * 100;
-            let decompressed = zstd::bulk::Decompressor::with_dictionary(old_content)
-                .unwrap()
-                .decompress(&compressed_diff.data, MAX_BYTES)
-                .unwrap();
-            let new_hash = blake3::hash(&decompressed);
-            self.contents.insert(new_hash, decompressed);
+        fn file_name(file_path: &FilePath) -> Option<&std::ffi::OsStr> {
+            Path::new(file_path.0.as_ref()).file_name()
+        }
+        let mut renames = Vec::new();
+        for (file_path, change) in &self.files {
+            let FileChange::Created { meta } = change else {
+                continue;
+            };
+            let Some(candidates) = meta.content_hash().and_then(|hash| removed.get_mut(&hash))
+            else {
+                continue;
+            };
+            let same_meta = |from: &&FilePath| matches!(&self.files[*from], FileChange::Removed { old_meta } if old_meta == meta);
+            let position = candidates
+                .iter()
+                .position(|from| same_meta(from) && file_name(from) == file_name(file_path))
+                .or_else(|| candidates.iter().position(same_meta));
+            if let Some(position) = position {
+                let from = candidates.swap_remove(position);
+                renames.push((file_path.clone(), from.clone()));
+            }
         }
+        for (file_path, from) in renames {
+            if let Some(FileChange::Created { meta }) = self.files.remove(&file_path) {
+                self.files.remove(&from);
+                self.files
+                    .insert(file_path, FileChange::Renamed { from, meta });
+            }
+        }
+    }
 
-        Ok(())
+    /// Removes the changes whose content isn't in `content_store` and returns them.
+    fn take_missing_content(&mut self, content_store: &ContentStore) -> FsStateDiff {
+        let (missing, available) =
+            std::mem::take(&mut self.files)
+                .into_iter()
+                .partition(|(_, change)| {
+                    change
+                        .new_content_hash()
+                        .is_some_and(|hash| !content_store.has(&hash))
+                });
+        self.files = available;
+        FsStateDiff { files: missing }
     }
 }
 
-#[derive(Debug, Clone, Encode, Decode)]
-pub struct Node {
-    this_state: FsState,
-    other_state: FsState,
-    conflicts: Vec<FilePath>,
+/// A content that changed or went away on disk since it was hashed. The change using
+/// it is stale, and its file has to be hashed again.
+#[derive(Debug)]
+struct ContentChanged {
+    hash: ContentHash,
 }
 
-#[derive(Debug, Clone, Encode, Decode)]
-pub struct ContentDiff {
-    new_content: Vec<Vec<u8>>,
-    modified_content: Vec<CompressedDiff>,
+impl std::fmt::Display for ContentChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Content {} changed on disk since it was hashed",
+            self.hash
+        )
+    }
 }
 
-#[derive(Debug, Clone, Encode, Decode)]
-pub struct CompressedDiff {
-    #[bincode(with_serde)]
-    old_hash: ContentHash,
-    data: Vec<u8>,
-}
+impl std::error::Error for ContentChanged {}
+
+#[derive(Default, Debug)]
+pub struct ContentStore {
+    blobs: BlobStore,
+    new_contents: HashSet<ContentHash>,
+    /// Files whose content hash is known, by absolute path. Lets unchanged files skip
+    /// rehashing, and their content be read back lazily.
+    files: HashMap<PathBuf, (FileStat, ContentHash)>,
+    file_for_hash: HashMap<ContentHash, PathBuf>,
+    /// Contents being received in chunks.
+    downloads: HashMap<ContentHash, Download>,
+    /// Contents received in chunks since the last collection. The changes using them
+    /// follow once the other node sees the acknowledgement, so they are kept until then.
+    downloaded: HashSet<ContentHash>,
+    /// Whether large contents are sent as content-defined chunks, see [`Features::DEDUP`].
+    dedup: bool,
+    dedup_index: DedupIndex,
+    /// The root the `files` are in, unless contents are kept in memory.
+    root: Option<PathBuf>,
+    /// What the other node may send, and what a message to it may carry.
+    limits: Limits,
+}
+
+impl ContentStore {
+    /// A store that keeps contents in the cache directory of `root` instead of memory.
+    /// The versions of unresolved conflicts are kept from the last run.
+    pub fn on_disk(root: &Path, options: &SyncOptions) -> Result<Self> {
+        let conflicts = load_conflicts(root);
+        let keep = conflicts
+            .iter()
+            .flat_map(Conflict::content_hashes)
+            .collect();
+        Ok(Self {
+            blobs: BlobStore::on_disk(root, &keep)?,
+            dedup: options.features.contains(Features::DEDUP),
+            root: Some(root.to_path_buf()),
+            limits: options.limits,
+            ..Default::default()
+        })
+    }
+
+    pub fn add(&mut self, content: Vec<u8>) -> Result<ContentHash> {
+        let hash = blake3::hash(&content);
+        self.insert(hash, content)?;
+        Ok(hash)
+    }
+
+    pub fn insert(&mut self, hash: ContentHash, content: Vec<u8>) -> Result<()> {
+        if self.blobs.insert(hash, content)? {
+            self.new_contents.insert(hash);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, hash: &ContentHash) -> Result<Cow<'_, [u8]>> {
+        if let Some(content) = self.blobs.get(hash)? {
+            return Ok(content);
+        }
+        if !self.file_for_hash.contains_key(hash) {
+            bail!("Content not found in content store");
+        }
+        for path in self.content_files(*hash) {
+            match std::fs::read(path) {
+                Ok(content) if blake3::hash(&content) == *hash => return Ok(Cow::Owned(content)),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to read content from {}", path.display()))
+                }
+            }
+        }
+        Err(ContentChanged { hash: *hash }.into())
+    }
+
+    /// Files that had content `hash` when they were hashed, the last one first.
+    fn content_files(&self, hash: ContentHash) -> impl Iterator<Item = &Path> {
+        let last = self.file_for_hash.get(&hash);
+        let others = self
+            .files
+            .iter()
+            .filter(move |(path, (_, file_hash))| *file_hash == hash && Some(*path) != last)
+            .map(|(path, _)| path);
+        last.into_iter().chain(others).map(PathBuf::as_path)
+    }
+
+    /// A file that had content `hash` when it was hashed, and is still there.
+    fn content_file(&self, hash: &ContentHash) -> Result<&Path> {
+        if !self.file_for_hash.contains_key(hash) {
+            bail!("Content not found in content store");
+        }
+        self.content_files(*hash)
+            .find(|path| path_exists(path))
+            .ok_or_else(|| ContentChanged { hash: *hash }.into())
+    }
+
+    /// Forgets the files of a content that changed on disk, so they are hashed again.
+    fn forget_changed_content(&mut self, hash: &ContentHash) {
+        self.file_for_hash.remove(hash);
+        self.files.retain(|_, (_, file_hash)| file_hash != hash);
+    }
+
+    /// Reads part of a content, without loading all of it if it is on disk.
+    pub fn read_content_at(&self, hash: &ContentHash, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if let Some(chunk) = self.blobs.read_at(hash, offset, len)? {
+            return Ok(chunk);
+        }
+        let path = self.content_file(hash)?;
+        read_file_at(path, offset, len)
+    }
+
+    fn open_content(&self, hash: &ContentHash) -> Result<Box<dyn Read + '_>> {
+        if let Some(reader) = self.blobs.open(hash)? {
+            return Ok(reader);
+        }
+        let path = self.content_file(hash)?;
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Box::new(file))
+    }
+
+    pub fn content_len(&self, hash: &ContentHash) -> Result<u64> {
+        if let Some(len) = self.blobs.content_len(hash)? {
+            return Ok(len);
+        }
+        let path = self.content_file(hash)?;
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    /// Stores a chunk of a content streamed by the other node. Returns how much of it
+    /// has arrived, or `None` if the chunk is out of order or the assembled content
+    /// doesn't match its hash, in which case the content has to be sent again.
+    pub fn receive_chunk(
+        &mut self,
+        hash: ContentHash,
+        size: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Option<u64>> {
+        if self.has(&hash) {
+            return Ok(Some(size));
+        }
+        if offset == 0 {
+            let download = Download::new(size, self.blobs.partial_path(&hash))?;
+            self.downloads.insert(hash, download);
+        }
+        let Some(download) = self.downloads.get_mut(&hash) else {
+            return Ok(None);
+        };
+        if !download.write(offset, data)? {
+            self.downloads.remove(&hash);
+            return Ok(None);
+        }
+        if !download.is_complete() {
+            return Ok(Some(download.received()));
+        }
+        let download = self.downloads.remove(&hash).unwrap();
+        let Some(content) = download.finish(&hash) else {
+            error!(%hash, "Received content doesn't match its hash");
+            return Ok(None);
+        };
+        self.blobs.insert_partial(hash, content)?;
+        self.downloaded.insert(hash);
+        Ok(Some(size))
+    }
+
+    /// Size of the content `change` writes, if the other node doesn't have it yet and
+    /// it is too large to send in a message.
+    fn streamed_size(&mut self, change: &FileChange) -> Result<Option<u64>> {
+        let Some(hash) = change.new_content_hash() else {
+            return Ok(None);
+        };
+        if !self.new_contents.contains(&hash) {
+            return Ok(None);
+        }
+        let size = self.content_len(&hash)?;
+        if size <= CHUNKED_CONTENT_BYTES {
+            return Ok(None);
+        }
+        // the other node has most of it, the rest fits in a message
+        if self
+            .new_chunk_bytes(&hash)?
+            .is_some_and(|bytes| bytes <= CHUNKED_CONTENT_BYTES)
+        {
+            return Ok(None);
+        }
+        Ok(Some(size))
+    }
+
+    /// Bytes of content `change` adds to a message, if the other node doesn't have it
+    /// yet.
+    fn message_size(&self, change: &FileChange) -> u64 {
+        match change.new_content_hash() {
+            Some(hash) if self.new_contents.contains(&hash) => self.content_len(&hash).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Content-defined chunks of `hash`, computed once.
+    fn recipe(&mut self, hash: &ContentHash) -> Result<Arc<[ChunkInfo]>> {
+        if let Some(recipe) = self.dedup_index.recipe(hash) {
+            return Ok(recipe);
+        }
+        let chunks = chunk_content(self.open_content(hash)?)
+            .with_context(|| format!("Failed to chunk content {hash}"))?;
+        Ok(self.dedup_index.add_recipe(*hash, chunks))
+    }
+
+    /// Chunks the contents the other node has that weren't looked at yet, so their
+    /// chunks can be referred to.
+    fn index_peer_contents(&mut self) {
+        let contents: Vec<_> = self
+            .blobs
+            .hashes()
+            .chain(self.file_for_hash.keys().copied())
+            .filter(|hash| {
+                !self.new_contents.contains(hash) && !self.dedup_index.knows_peer_content(hash)
+            })
+            .collect();
+        for hash in contents {
+            let recipe = match self.content_len(&hash) {
+                Ok(len) if len >= MIN_DEDUP_BYTES => match self.recipe(&hash) {
+                    Ok(recipe) => Some(recipe),
+                    Err(e) => {
+                        warn!("{e:#}");
+                        continue;
+                    }
+                },
+                Ok(_) => None,
+                // gone since, a later collection forgets it
+                Err(_) => continue,
+            };
+            self.dedup_index.add_peer_content(hash, recipe.as_deref());
+        }
+    }
+
+    /// Whether a content of `len` bytes can be sent as chunks. The other node assembles
+    /// it in full, so it has to fit in a message like a content sent whole.
+    fn can_chunk(&self, len: u64) -> bool {
+        self.dedup && (MIN_DEDUP_BYTES..=self.limits.message_content_bytes()).contains(&len)
+    }
+
+    /// Bytes of `hash` in chunks the other node lacks, if it can be sent as chunks.
+    fn new_chunk_bytes(&mut self, hash: &ContentHash) -> Result<Option<u64>> {
+        if !self.can_chunk(self.content_len(hash)?) {
+            return Ok(None);
+        }
+        self.index_peer_contents();
+        let recipe = self.recipe(hash)?;
+        let bytes = recipe
+            .iter()
+            .filter(|chunk| self.dedup_index.peer_chunk(&chunk.hash).is_none())
+            .map(|chunk| chunk.len as u64)
+            .sum();
+        Ok(Some(bytes))
+    }
+
+    /// `hash` as chunks, if it is large enough and shares chunks with contents the
+    /// other node has. Must be called while `hash` is still new to the other node.
+    fn chunked_content(&mut self, hash: &ContentHash) -> Result<Option<ChunkedContent>> {
+        if !self.can_chunk(self.content_len(hash)?) {
+            return Ok(None);
+        }
+        self.index_peer_contents();
+        let recipe = self.recipe(hash)?;
+        let index = &self.dedup_index;
+        if !recipe
+            .iter()
+            .any(|chunk| index.peer_chunk(&chunk.hash).is_some())
+        {
+            return Ok(None);
+        }
+        let mut parts = Vec::with_capacity(recipe.len());
+        for chunk in recipe.iter() {
+            if let Some(source) = self.dedup_index.peer_chunk(&chunk.hash) {
+                parts.push(ChunkPart::Known {
+                    hash: chunk.hash,
+                    source,
+                });
+                continue;
+            }
+            let data = self.read_content_at(hash, chunk.offset, chunk.len as u64)?;
+            if blake3::hash(&data) != chunk.hash {
+                return Err(ContentChanged { hash: *hash }.into());
+            }
+            let data = zstd::bulk::compress(&data, 0).context("Failed to compress chunk")?;
+            parts.push(ChunkPart::New {
+                len: chunk.len,
+                data,
+            });
+        }
+        Ok(Some(ChunkedContent { hash: *hash, parts }))
+    }
+
+    /// Adds a content the other node doesn't have to `content_diff`: as chunks if it
+    /// shares some with contents the other node has, else as a delta against `old` if
+    /// there is one, or whole.
+    fn add_to_content_diff(
+        &mut self,
+        content_diff: &mut ContentDiff,
+        hash: ContentHash,
+        old: Option<(ContentHash, &[u8])>,
+    ) -> Result<()> {
+        if let Some(chunked) = self.chunked_content(&hash)? {
+            self.new_contents.remove(&hash);
+            content_diff.chunked_content.push(chunked);
+            return Ok(());
+        }
+        let content = self.get(&hash)?;
+        let result = match old {
+            Some((old_hash, old_content)) => {
+                content_diff.add_modified_content(old_hash, old_content, hash, &content)
+            }
+            None => {
+                content_diff.add_new_content(hash, content.to_vec());
+                Ok(())
+            }
+        };
+        self.new_contents.remove(&hash);
+        result
+    }
+
+    /// Rebuilds a content of `len` bytes sent as chunks, on disk unless contents are kept
+    /// in memory. Returns `None` if a chunk the other node expected this node to have
+    /// can't be read, or the content doesn't match its hash.
+    fn assemble_chunked_content(
+        &self,
+        chunked: &ChunkedContent,
+        len: u64,
+    ) -> Result<Option<PartialContent>> {
+        let mut content = Download::new(len, self.blobs.partial_path(&chunked.hash))?;
+        for part in &chunked.parts {
+            let chunk = match part {
+                ChunkPart::Known { hash, source } => self
+                    .read_content_at(&source.content, source.offset, source.len.into())
+                    .ok()
+                    .filter(|chunk| blake3::hash(chunk) == *hash),
+                ChunkPart::New { len, data } => zstd::bulk::decompress(data, *len as usize).ok(),
+            };
+            let Some(chunk) = chunk else {
+                return Ok(None);
+            };
+            if !content.write(content.received(), &chunk)? {
+                return Ok(None);
+            }
+        }
+        Ok(content.finish(&chunked.hash))
+    }
+
+    pub fn remove(&mut self, hash: &ContentHash) -> Result<()> {
+        self.file_for_hash.remove(hash);
+        self.blobs.remove(hash)
+    }
+
+    pub fn has(&self, hash: &ContentHash) -> bool {
+        self.blobs.contains(hash) || self.file_for_hash.contains_key(hash)
+    }
+
+    /// Content hash of the file at `path`, if it is unchanged since it was last hashed.
+    fn known_file_hash(&self, path: &Path, stat: &FileStat) -> Option<ContentHash> {
+        self.files
+            .get(path)
+            .filter(|(known_stat, _)| known_stat == stat)
+            .map(|(_, hash)| *hash)
+    }
+
+    /// When the files `diff` writes were modified, as of their last hashing.
+    fn mtimes(&self, diff: &FsStateDiff) -> BTreeMap<FilePath, i64> {
+        let Some(root) = &self.root else {
+            return BTreeMap::new();
+        };
+        diff.files
+            .iter()
+            .filter(|(_, change)| change.new_content_hash().is_some())
+            .filter_map(|(file_path, _)| {
+                let (stat, _) = self.files.get(&file_path.to_absolute(root))?;
+                Some((file_path.clone(), stat.mtime_ns()))
+            })
+            .collect()
+    }
+
+    /// Follows a file moved by [`FileChange::Renamed`], so it isn't hashed again.
+    fn record_rename(&mut self, from: &Path, to: PathBuf) {
+        let Some((stat, hash)) = self.files.remove(from) else {
+            return;
+        };
+        if self
+            .file_for_hash
+            .get(&hash)
+            .is_some_and(|path| path == from)
+        {
+            self.file_for_hash.insert(hash, to.clone());
+        }
+        self.files.insert(to, (stat, hash));
+    }
+
+    fn record_file(&mut self, path: PathBuf, stat: FileStat, hash: ContentHash) {
+        if !self.has(&hash) {
+            self.new_contents.insert(hash);
+        }
+        self.file_for_hash.insert(hash, path.clone());
+        self.files.insert(path, (stat, hash));
+    }
+
+    /// Removes all contents not in `live`.
+    pub fn retain(&mut self, live: &HashSet<ContentHash>) -> Result<GcStats> {
+        let downloaded = std::mem::take(&mut self.downloaded);
+        let removed_blobs = self
+            .blobs
+            .retain(|hash| live.contains(hash) || downloaded.contains(hash))?;
+        self.dedup_index.retain(live);
+        self.new_contents.retain(|hash| live.contains(hash));
+        self.file_for_hash.retain(|hash, _| live.contains(hash));
+        self.files.retain(|_, (_, hash)| live.contains(hash));
+        Ok(GcStats {
+            removed_blobs,
+            remaining_blobs: self.blobs.len(),
+            files: self.files.len(),
+        })
+    }
+
+    pub fn seen_from_other(&mut self, hash: &ContentHash) {
+        self.new_contents.remove(hash);
+    }
+
+    /// Contents of `diff` the other node doesn't have. Changes whose content changed on
+    /// disk since it was hashed are stale: they are taken out of `diff` and returned, and
+    /// their files are hashed again when they are refreshed.
+    pub fn create_content_diff(
+        &mut self,
+        diff: &mut FsStateDiff,
+    ) -> Result<(ContentDiff, FsStateDiff)> {
+        let mut content_diff = ContentDiff::new();
+        let mut stale = Vec::new();
+        for (file_path, change) in &diff.files {
+            if let Err(e) = self.add_change_content(&mut content_diff, change) {
+                self.check_stale(e)?;
+                stale.push(file_path.clone());
+            }
+        }
+        let stale = FsStateDiff {
+            files: stale
+                .into_iter()
+                .map(|file_path| {
+                    let change = diff.files.remove(&file_path).unwrap();
+                    (file_path, change)
+                })
+                .collect(),
+        };
+        Ok((content_diff, stale))
+    }
+
+    /// Returns `error`, unless it is a [`ContentChanged`]. The files of that content are
+    /// forgotten then, so they are hashed again.
+    fn check_stale(&mut self, error: anyhow::Error) -> Result<()> {
+        let Some(changed) = error.downcast_ref::<ContentChanged>() else {
+            return Err(error);
+        };
+        warn!("{changed}, hashing it again");
+        self.forget_changed_content(&changed.hash);
+        Ok(())
+    }
+
+    /// Adds the contents `change` needs that the other node doesn't have.
+    fn add_change_content(
+        &mut self,
+        content_diff: &mut ContentDiff,
+        change: &FileChange,
+    ) -> Result<()> {
+        match change {
+            FileChange::Created { meta } | FileChange::Renamed { meta, .. } => {
+                if let Some(hash) = meta.content_hash() {
+                    if self.new_contents.contains(&hash) {
+                        self.add_to_content_diff(content_diff, hash, None)?;
+                    }
+                }
+            }
+            FileChange::Modified { old_meta, new_meta } => {
+                let new_hash = new_meta
+                    .content_hash()
+                    .filter(|hash| self.new_contents.contains(hash));
+                let old_hash = old_meta.content_hash();
+                let old_content_is_new =
+                    old_hash.is_some_and(|hash| self.new_contents.remove(&hash));
+                let old_content =
+                    old_hash.and_then(|hash| Some((hash, self.get(&hash).ok()?.into_owned())));
+                if let Some((old_hash, old_content)) = &old_content {
+                    if old_content_is_new {
+                        content_diff.add_new_content(*old_hash, old_content.clone());
+                    }
+                }
+
+                if let Some(new_hash) = new_hash {
+                    let old = old_content.as_ref().map(|(hash, c)| (*hash, &c[..]));
+                    self.add_to_content_diff(content_diff, new_hash, old)?;
+                }
+            }
+            FileChange::Removed { old_meta } => {
+                if let Some(hash) = old_meta.content_hash() {
+                    // an overridden node gets its new contents this way
+                    if self.new_contents.contains(&hash) {
+                        if let Err(e) = self.add_to_content_diff(content_diff, hash, None) {
+                            warn!(%hash, "Failed to send old content: {e:#}");
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the contents sent by the other node. Contents that can't be decompressed
+    /// or don't match the hash they were sent for are dropped, so the changes using them
+    /// show up as missing content and are sent again in full.
+    pub fn apply_content_diff_from_other(&mut self, content_diff: &ContentDiff) -> Result<()> {
+        for content in &content_diff.new_content {
+            self.insert_from_other(content.hash, content.data.clone())?;
+        }
+
+        // uncompressed, a content sent in a message fits in a frame too
+        let max_len = self.limits.max_frame_bytes as usize;
+        for content in &content_diff.compressed_content {
+            let decompressed = zstd::stream::Decoder::new(&content.data[..])
+                .map_err(Into::into)
+                .and_then(|decoder| decompress_bounded(decoder, max_len));
+            match decompressed {
+                Ok(data) => self.insert_from_other(content.hash, data)?,
+                Err(e) => error!(hash = %content.hash, "Failed to decompress content: {e:#}"),
+            }
+        }
+
+        for compressed_diff in &content_diff.modified_content {
+            let Ok(old_content) = self.get(&compressed_diff.old_hash) else {
+                error!(
+                    hash = %compressed_diff.old_hash,
+                    "Received a delta against content that isn't in the store"
+                );
+                continue;
+            };
+            let decompressed =
+                zstd::stream::Decoder::with_dictionary(&compressed_diff.data[..], &old_content)
+                    .map_err(Into::into)
+                    .and_then(|decoder| decompress_bounded(decoder, MAX_DELTA_BYTES));
+            match decompressed {
+                Ok(data) => self.insert_from_other(compressed_diff.new_hash, data)?,
+                Err(e) => {
+                    error!(hash = %compressed_diff.new_hash, "Failed to decompress delta: {e:#}")
+                }
+            }
+        }
+
+        // last, since chunks may be in contents sent along
+        for chunked in &content_diff.chunked_content {
+            let Some(len) = chunked.content_len() else {
+                error!(hash = %chunked.hash, "Chunked content with a part longer than a chunk");
+                continue;
+            };
+            if let Err(e) = self.limits.check_file_bytes(len) {
+                error!(hash = %chunked.hash, "{e:#}");
+                continue;
+            }
+            // like a content sent whole, so a few parts can't stand for a huge content
+            if len > self.limits.message_content_bytes() {
+                error!(hash = %chunked.hash, len, "Chunked content larger than a message");
+                continue;
+            }
+            match self.assemble_chunked_content(chunked, len)? {
+                Some(content) => {
+                    self.blobs.insert_partial(chunked.hash, content)?;
+                }
+                None => error!(hash = %chunked.hash, "Failed to rebuild content from chunks"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert_from_other(&mut self, hash: ContentHash, content: Vec<u8>) -> Result<()> {
+        if blake3::hash(&content) != hash {
+            error!(%hash, "Received content doesn't match its hash");
+            return Ok(());
+        }
+        self.blobs.insert(hash, content)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Node {
+    this_state: FsState,
+    other_state: FsState,
+    conflicts: Vec<Conflict>,
+    /// Node id of the other side, `other_state` is saved for it to resume from.
+    peer: Option<String>,
+    /// Host name of the other side, used in the names of conflict copies.
+    peer_host: Option<String>,
+    /// Changes sent to the other node that it hasn't responded to yet, oldest first.
+    in_flight: VecDeque<FsStateDiff>,
+    /// Large contents streamed to the other node, oldest first. The changes using them
+    /// are held back until the other node has stored the content.
+    uploads: VecDeque<Upload>,
+    /// Paths whose changes didn't fit in the limits of a message. They are sent when
+    /// the other node responds.
+    deferred: BTreeSet<FilePath>,
+    /// Paths whose changes were dropped from a message, because their content changed
+    /// on disk since it was hashed. They are refreshed before anything else.
+    stale: BTreeSet<FilePath>,
+}
+
+#[derive(Debug, Clone, Default, Encode, Decode)]
+pub struct ContentDiff {
+    new_content: Vec<FullContent>,
+    /// Contents too large for a delta, compressed on their own.
+    compressed_content: Vec<FullContent>,
+    modified_content: Vec<CompressedDiff>,
+    /// Contents sharing chunks with contents the receiver has.
+    chunked_content: Vec<ChunkedContent>,
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct FullContent {
+    #[bincode(with_serde)]
+    hash: ContentHash,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct CompressedDiff {
+    #[bincode(with_serde)]
+    old_hash: ContentHash,
+    /// Hash of the decompressed content.
+    #[bincode(with_serde)]
+    new_hash: ContentHash,
+    data: Vec<u8>,
+}
 
 impl ContentDiff {
     pub fn new() -> Self {
-        ContentDiff {
-            new_content: Vec::new(),
-            modified_content: Vec::new(),
-        }
+        Self::default()
     }
 
-    pub fn add_new_content(&mut self, content: Vec<u8>) {
-        self.new_content.push(content);
+    pub fn add_new_content(&mut self, hash: ContentHash, content: Vec<u8>) {
+        self.new_content.push(FullContent {
+            hash,
+            data: content,
+        });
     }
 
     pub fn add_modified_content(
         &mut self,
         old_hash: ContentHash,
         old_content: &[u8],
+        new_hash: ContentHash,
         new_content: &[u8],
-    ) {
-        let compressed_diff = zstd::bulk::Compressor::with_dictionary(0, &old_content)
-            .unwrap()
-            .compress(new_content)
-            .unwrap();
+    ) -> Result<()> {
+        if old_content.len() > MAX_DELTA_BYTES || new_content.len() > MAX_DELTA_BYTES {
+            return self.add_compressed_content(new_hash, new_content);
+        }
+        let compressed_diff = zstd::bulk::Compressor::with_dictionary(0, old_content)
+            .and_then(|mut c| c.compress(new_content))
+            .context("Failed to compress delta")?;
         self.modified_content.push(CompressedDiff {
             old_hash,
+            new_hash,
             data: compressed_diff,
         });
+        Ok(())
+    }
+
+    pub fn add_compressed_content(&mut self, hash: ContentHash, content: &[u8]) -> Result<()> {
+        let data = zstd::stream::encode_all(content, 0).context("Failed to compress content")?;
+        self.compressed_content.push(FullContent { hash, data });
+        Ok(())
     }
 }
 
@@ -476,9 +1761,35 @@ pub enum NodeMessage {
     Changes {
         content_diff: ContentDiff,
         diff: FsStateDiff,
+        /// When the written files were last modified, in nanoseconds since the epoch, see
+        /// [`ConflictPolicy::NewestWins`].
+        mtimes: BTreeMap<FilePath, i64>,
     },
     ChangesResponse {
         accepted_diff: FsStateDiff,
+        /// Contents that didn't arrive intact, the changes using them are sent again.
+        #[bincode(with_serde)]
+        missing_content: Vec<ContentHash>,
+    },
+    /// Part of a content too large for `Changes`, chunks of a content arrive in order.
+    Chunk {
+        #[bincode(with_serde)]
+        hash: ContentHash,
+        size: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// The first `received` bytes of a content arrived. Once that is all of it, the
+    /// content is stored and the changes using it can be sent.
+    ChunkAck {
+        #[bincode(with_serde)]
+        hash: ContentHash,
+        received: u64,
+    },
+    /// A chunk was out of order or the content didn't match its hash.
+    ChunkRejected {
+        #[bincode(with_serde)]
+        hash: ContentHash,
     },
 }
 
@@ -488,16 +1799,47 @@ pub enum RefreshRequest {
     Path(PathBuf),
 }
 
+impl NodeMessage {
+    /// Rejects paths that could lead outside the root, before any of the message is used.
+    fn validate_paths(&self) -> Result<()> {
+        match self {
+            NodeMessage::Changes { diff, .. } => diff.validate_paths(),
+            NodeMessage::ChangesResponse { accepted_diff, .. } => accepted_diff.validate_paths(),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl std::fmt::Debug for NodeMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             NodeMessage::Changes { diff, .. } => {
                 f.debug_struct("Changes").field("diff", diff).finish()
             }
-            NodeMessage::ChangesResponse { accepted_diff } => f
+            NodeMessage::ChangesResponse {
+                accepted_diff,
+                missing_content,
+            } => f
                 .debug_struct("ChangesResponse")
                 .field("accepted_diff", accepted_diff)
+                .field("missing_content", missing_content)
+                .finish(),
+            NodeMessage::Chunk {
+                hash, size, offset, ..
+            } => f
+                .debug_struct("Chunk")
+                .field("hash", hash)
+                .field("size", size)
+                .field("offset", offset)
                 .finish(),
+            NodeMessage::ChunkAck { hash, received } => f
+                .debug_struct("ChunkAck")
+                .field("hash", hash)
+                .field("received", received)
+                .finish(),
+            NodeMessage::ChunkRejected { hash } => {
+                f.debug_struct("ChunkRejected").field("hash", hash).finish()
+            }
         }
     }
 }
@@ -506,16 +1848,72 @@ impl std::fmt::Debug for NodeMessage {
 pub struct NodeInit {
     this_state: FsState,
     other_state: Option<FsState>,
+    /// The overriding node also leads the init: its sync rules are used by both nodes.
     should_override: bool,
+    rules: SyncRules,
+    node_id: String,
+    other_node_id: Option<String>,
+    other_host: Option<String>,
+    /// Last state agreed with each peer, by node id.
+    peer_states: BTreeMap<String, FsState>,
+    /// Unresolved conflicts of the last run.
+    conflicts: Vec<Conflict>,
+    /// Paths the other node has unresolved conflicts on.
+    other_conflicts: Vec<FilePath>,
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub enum NodeInitMessage {
-    NodeAnnouncement { state: FsState },
-    Override { content_diff: ContentDiff },
+    /// Sent by both nodes before anything else, must stay the first variant.
+    Hello(Hello),
+    NodeAnnouncement {
+        state: FsState,
+        node_id: String,
+        host: String,
+        /// Peers this node saved a common state with, and a digest of it, see
+        /// [`NodeInit::base_digest`]. Resuming needs both nodes to have saved the same
+        /// state.
+        #[bincode(with_serde)]
+        resumable_peers: Vec<(String, ContentHash)>,
+        rules: SyncRules,
+        /// Paths with unresolved conflicts, an override leaves them as they are.
+        conflicts: Vec<FilePath>,
+    },
+    Override {
+        content_diff: ContentDiff,
+        /// Paths left as they are on the receiving node. Either one of the nodes has an
+        /// unresolved conflict on them, or their contents don't fit in this message and
+        /// are sent once the init is done.
+        held_back: Vec<FilePath>,
+    },
     OverrideAck,
 }
 
+impl NodeInitMessage {
+    /// Rejects paths that could lead outside the root, see [`NodeMessage::validate_paths`].
+    fn validate_paths(&self) -> Result<()> {
+        let mut paths: Box<dyn Iterator<Item = &FilePath>> = match self {
+            NodeInitMessage::NodeAnnouncement {
+                state, conflicts, ..
+            } => Box::new(state.files.keys().chain(conflicts)),
+            NodeInitMessage::Override { held_back, .. } => Box::new(held_back.iter()),
+            NodeInitMessage::Hello(_) | NodeInitMessage::OverrideAck => return Ok(()),
+        };
+        paths.try_for_each(FilePath::validate)
+    }
+
+    /// Rejects a tree beyond the [`Limits`] of this node.
+    fn check_limits(&self, limits: &Limits) -> Result<()> {
+        match self {
+            NodeInitMessage::NodeAnnouncement {
+                state, conflicts, ..
+            } => limits.check_tree_files(state.files.len().max(conflicts.len())),
+            NodeInitMessage::Override { held_back, .. } => limits.check_tree_files(held_back.len()),
+            NodeInitMessage::Hello(_) | NodeInitMessage::OverrideAck => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
 pub enum AnyNodeMessage {
     Init(NodeInitMessage),
@@ -525,66 +1923,225 @@ pub enum AnyNodeMessage {
 impl NodeInit {
     pub fn from_disk(
         root: &Path,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
         should_override: bool,
     ) -> Result<Self> {
-        let this_state = FsState::from_disk(root, content_store)?;
+        if let Some(cache) = StateCache::load(root) {
+            cache.restore(root, content_store);
+        }
+        let rules = SyncRules::load(root, options)?;
+        options.ignore_filter.set_rules(&rules)?;
+        let this_state = FsState::from_disk(root, options, content_store)?;
         Ok(Self {
             this_state,
             other_state: None,
             should_override,
+            rules,
+            node_id: load_or_create_node_id(root)?,
+            other_node_id: None,
+            other_host: None,
+            peer_states: load_peer_states(root),
+            conflicts: load_conflicts(root),
+            other_conflicts: Vec::new(),
         })
     }
-    pub fn announce(&self) -> NodeInitMessage {
+    /// The first message of the init, only sent by the leading node. The other node
+    /// announces itself once it has adopted the leader's sync rules.
+    pub fn announce(&self) -> Option<NodeInitMessage> {
+        self.should_override.then(|| self.announcement())
+    }
+
+    fn announcement(&self) -> NodeInitMessage {
         NodeInitMessage::NodeAnnouncement {
             state: self.this_state.clone(),
+            node_id: self.node_id.clone(),
+            host: conflict::hostname(),
+            resumable_peers: self
+                .peer_states
+                .iter()
+                .map(|(peer, state)| (peer.clone(), self.base_digest(state)))
+                .collect(),
+            rules: self.rules.clone(),
+            conflicts: self.conflicts.iter().map(|c| c.path.clone()).collect(),
         }
     }
 
-    fn override_other(&mut self, content_store: &mut ContentStore) -> NodeInitMessage {
-        let diff = self.this_state.diff(self.other_state.as_ref().unwrap());
-        let content_diff = content_store
-            .create_content_diff(&diff)
-            .expect("Failed to create content diff");
-        NodeInitMessage::Override { content_diff }
+    /// Hash of a state saved with a peer, leaving out the paths with conflicts. After a
+    /// conflict each node saved the other's version there, and those paths wait for the
+    /// user anyway.
+    fn base_digest(&self, base: &FsState) -> ContentHash {
+        let mut hasher = blake3::Hasher::new();
+        for entry in &base.files {
+            if self.conflicts.iter().any(|c| c.path == *entry.0) {
+                continue;
+            }
+            let encoded = bincode::encode_to_vec(entry, bincode::config::standard())
+                .expect("a state can always be encoded");
+            hasher.update(&encoded);
+        }
+        hasher.finalize()
+    }
+
+    fn new_node(&self, other_state: FsState) -> Node {
+        Node {
+            peer: self.other_node_id.clone(),
+            peer_host: self.other_host.clone(),
+            conflicts: self.conflicts.clone(),
+            ..Node::new(self.this_state.clone(), other_state)
+        }
+    }
+
+    fn override_other(&mut self, content_store: &mut ContentStore) -> Result<NodeInitMessage> {
+        let other_state = self.other_state.as_mut().unwrap();
+        // the state the other node ends up in
+        let mut target = self.this_state.clone();
+        let mut held_back = Vec::new();
+        let conflicts: BTreeSet<&FilePath> = (self.conflicts.iter().map(|c| &c.path))
+            .chain(&self.other_conflicts)
+            .collect();
+        let mut content_bytes = 0;
+        for (file_path, change) in other_state.diff(&self.this_state).files {
+            let size = content_store.message_size(&change);
+            // a stale content is sent once its file was hashed again
+            let streamed = match content_store.streamed_size(&change) {
+                Ok(size) => size.is_some(),
+                Err(e) => content_store.check_stale(e).map(|()| true)?,
+            };
+            // conflicts are left for the user to resolve
+            let hold_back = conflicts.contains(&file_path)
+                || streamed
+                || content_bytes + size > content_store.limits.message_content_bytes();
+            if hold_back {
+                match other_state.files.get(&file_path) {
+                    Some(meta) => target.files.insert(file_path.clone(), meta.clone()),
+                    None => target.files.remove(&file_path),
+                };
+                held_back.push(file_path);
+            } else {
+                content_bytes += size;
+            }
+        }
+        let mut diff = target.diff(other_state);
+        let (content_diff, stale) = content_store
+            .create_content_diff(&mut diff)
+            .context("Failed to create content diff")?;
+        for file_path in stale.files.into_keys() {
+            match other_state.files.get(&file_path) {
+                Some(meta) => target.files.insert(file_path.clone(), meta.clone()),
+                None => target.files.remove(&file_path),
+            };
+            held_back.push(file_path);
+        }
+        *other_state = target;
+        Ok(NodeInitMessage::Override {
+            content_diff,
+            held_back,
+        })
     }
 
     pub fn handle_init_message(
         &mut self,
         root: &Path,
         message: NodeInitMessage,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
     ) -> Result<(Option<Node>, Option<NodeInitMessage>)> {
+        message.validate_paths()?;
+        message.check_limits(&options.limits)?;
         match message {
-            NodeInitMessage::NodeAnnouncement { state: other_state } => {
+            NodeInitMessage::Hello(_) => bail!("Unexpected hello after init started"),
+            NodeInitMessage::NodeAnnouncement {
+                state: other_state,
+                node_id,
+                host,
+                resumable_peers,
+                rules,
+                conflicts,
+            } => {
+                validate_node_id(&node_id)?;
+                self.other_conflicts = conflicts;
+                let response = if self.should_override {
+                    if rules != self.rules {
+                        bail!("Other node didn't adopt our sync rules");
+                    }
+                    None
+                } else {
+                    if rules != self.rules {
+                        info!("Using the sync rules of the other node");
+                        options.ignore_filter.set_rules(&rules)?;
+                        self.rules = rules;
+                        self.this_state = FsState::from_disk(root, options, content_store)?;
+                    }
+                    Some(self.announcement())
+                };
                 // mark all hashes from other as seen
-                for (_, hash) in &other_state.files {
-                    content_store.seen_from_other(&hash.content_hash);
+                for hash in other_state.files.values().filter_map(|m| m.content_hash()) {
+                    content_store.seen_from_other(&hash);
+                }
+                // Both sides compare the same two digests, so they make the same decision
+                // without an extra round trip. Each saves its state on its own, so after a
+                // dropped connection one may have saved a later state than the other.
+                let base = self.peer_states.remove(&node_id).filter(|base| {
+                    let digest = self.base_digest(base);
+                    let same = resumable_peers.contains(&(self.node_id.clone(), digest));
+                    if !same
+                        && resumable_peers
+                            .iter()
+                            .any(|(peer, _)| *peer == self.node_id)
+                    {
+                        warn!("The other node saved a different common state, not resuming");
+                    }
+                    same
+                });
+                self.other_node_id = Some(node_id);
+                self.other_host = Some(host);
+                if let Some(base) = base {
+                    info!("Resuming from the last state agreed with the other node");
+                    return Ok((Some(self.new_node(base)), response));
                 }
                 self.other_state = Some(other_state);
                 if self.should_override {
-                    Ok((None, Some(self.override_other(content_store))))
+                    warn!("No common state with the other node, overriding it");
+                    Ok((None, Some(self.override_other(content_store)?)))
                 } else {
-                    Ok((None, None))
+                    Ok((None, response))
                 }
             }
-            NodeInitMessage::Override { content_diff } => {
-                if self.other_state.is_none() {
+            NodeInitMessage::Override {
+                content_diff,
+                held_back,
+            } => {
+                let Some(target) = &mut self.other_state else {
                     bail!("Cannot apply override without other state");
+                };
+                for file_path in held_back {
+                    match self.this_state.files.get(&file_path) {
+                        Some(meta) => target.files.insert(file_path, meta.clone()),
+                        None => target.files.remove(&file_path),
+                    };
                 }
                 content_store.apply_content_diff_from_other(&content_diff)?;
-                let diff = self.this_state.diff(self.other_state.as_ref().unwrap());
+                let mut diff = self.this_state.diff(self.other_state.as_ref().unwrap());
+                let missing = diff.take_missing_content(content_store);
+                if !missing.is_empty() {
+                    bail!(
+                        "Content of {:?} didn't arrive intact from the other node",
+                        missing.files.keys().collect::<Vec<_>>()
+                    );
+                }
                 self.this_state
-                    .apply_diff_to_disk(&diff, root, content_store)?;
-                let node = Node::new(self.this_state.clone(), self.other_state.take().unwrap());
+                    .apply_diff_to_disk(&diff, root, options, content_store)?;
+                let other_state = self.other_state.take().unwrap();
+                let node = self.new_node(other_state);
                 Ok((Some(node), Some(NodeInitMessage::OverrideAck)))
             }
             NodeInitMessage::OverrideAck => {
-                if self.other_state.is_none() {
+                let Some(other_state) = self.other_state.take() else {
                     bail!("Cannot accept override ask without other state");
-                }
-                // other state has accepted our override.
-                let node = Node::new(self.this_state.clone(), self.this_state.clone());
+                };
+                // other state has accepted our override, apart from the held back paths
+                let node = self.new_node(other_state);
                 Ok((Some(node), None))
             }
         }
@@ -597,33 +2154,291 @@ impl Node {
             this_state,
             other_state,
             conflicts: Vec::new(),
+            peer: None,
+            peer_host: None,
+            in_flight: VecDeque::new(),
+            uploads: VecDeque::new(),
+            deferred: BTreeSet::new(),
+            stale: BTreeSet::new(),
         }
     }
 
-    pub fn messages_for_other(&mut self, content_store: &mut ContentStore) -> Result<NodeMessage> {
+    /// Changes the other node hasn't seen yet, e.g. the ones made while disconnected.
+    pub fn messages_for_other(
+        &mut self,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
         let diff = self.changes_for_other();
-        let content_diff = content_store.create_content_diff(&diff)?;
+        self.changes_message(diff, content_store)
+    }
+
+    fn changes_message(
+        &mut self,
+        mut diff: FsStateDiff,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        diff = self.since_sent(diff.files.into_keys());
+        // held back until the user resolves the conflict
+        diff.files.retain(|file_path, _| {
+            !self
+                .conflicts
+                .iter()
+                .any(|c| c.path == *file_path && c.diverged())
+        });
+        self.send_diff(diff, content_store)
+    }
+
+    /// Sends `diff` as it is, apart from the changes held back for their large content.
+    fn send_diff(
+        &mut self,
+        mut diff: FsStateDiff,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        self.hold_back_large_content(&mut diff, content_store)?;
+        diff.detect_renames();
+        self.defer_over_limits(&mut diff, content_store);
+        if diff.is_empty() {
+            return Ok(None);
+        }
+        let (content_diff, stale) = content_store.create_content_diff(&mut diff)?;
+        for (file_path, change) in stale.files {
+            self.stale.extend(change.renamed_from().cloned());
+            self.stale.insert(file_path);
+        }
+        if diff.is_empty() {
+            return Ok(None);
+        }
+        let mtimes = content_store.mtimes(&diff);
+        self.in_flight.push_back(diff.clone());
+        Ok(Some(NodeMessage::Changes {
+            content_diff,
+            diff,
+            mtimes,
+        }))
+    }
+
+    /// The changes of `file_paths` since what was sent to the other node so far. That
+    /// differs from the previous local state of a path when its change was held back.
+    fn since_sent(&self, file_paths: impl IntoIterator<Item = FilePath>) -> FsStateDiff {
+        let renamed_from: Vec<BTreeSet<&FilePath>> = self
+            .in_flight
+            .iter()
+            .map(|diff| {
+                diff.files
+                    .values()
+                    .filter_map(FileChange::renamed_from)
+                    .collect()
+            })
+            .collect();
+        let files = file_paths
+            .into_iter()
+            .filter_map(|file_path| {
+                let mut sent = self.other_state.files.get(&file_path);
+                for (in_flight, renamed_from) in self.in_flight.iter().zip(&renamed_from) {
+                    if let Some(change) = in_flight.files.get(&file_path) {
+                        sent = change.new_meta();
+                    } else if renamed_from.contains(&file_path) {
+                        sent = None;
+                    }
+                }
+                let change = FileChange::between(sent, self.this_state.files.get(&file_path))?;
+                Some((file_path, change))
+            })
+            .collect();
+        FsStateDiff { files }
+    }
+
+    /// Removes the changes whose content is new to the other node and too large for a
+    /// message, and starts streaming that content. The changes are sent once it arrived.
+    fn hold_back_large_content(
+        &mut self,
+        diff: &mut FsStateDiff,
+        content_store: &mut ContentStore,
+    ) -> Result<()> {
+        let mut held_back = Vec::new();
+        for (file_path, change) in &diff.files {
+            let size = match content_store.streamed_size(change) {
+                Ok(Some(size)) => size,
+                Ok(None) => continue,
+                Err(e) => {
+                    content_store.check_stale(e)?;
+                    self.stale.extend(change.renamed_from().cloned());
+                    self.stale.insert(file_path.clone());
+                    held_back.push(file_path.clone());
+                    continue;
+                }
+            };
+            let hash = change.new_content_hash().unwrap();
+            held_back.push(file_path.clone());
+            if !self.uploads.iter().any(|upload| upload.hash == hash) {
+                info!(path = ?file_path, size, "Streaming large file");
+                self.uploads.push_back(Upload::new(hash, size));
+            }
+        }
+        for file_path in held_back {
+            diff.files.remove(&file_path);
+        }
+        Ok(())
+    }
+
+    /// Leaves the changes beyond the [`Limits`] of a message for the messages after it.
+    /// Removed directories wait for the paths inside them, which may be among those.
+    fn defer_over_limits(&mut self, diff: &mut FsStateDiff, content_store: &ContentStore) {
+        let limits = &content_store.limits;
+        let removes_dir = |change: &FileChange| matches!(change, FileChange::Removed { old_meta } if *old_meta == FileMetadata::Directory);
+        let (removed_dirs, others): (Vec<_>, Vec<_>) = diff
+            .files
+            .iter()
+            .partition(|(_, change)| removes_dir(change));
+        let mut files = 0;
+        let mut content_bytes = 0;
+        let mut deferred = Vec::new();
+        // subdirectories first
+        for (file_path, change) in others.into_iter().chain(removed_dirs.into_iter().rev()) {
+            let size = content_store.message_size(change);
+            // at least one change per message, whatever its size
+            let fits = files == 0
+                || (files < limits.max_diff_files
+                    && content_bytes + size <= limits.message_content_bytes());
+            if fits && (deferred.is_empty() || !removes_dir(change)) {
+                files += 1;
+                content_bytes += size;
+            } else {
+                deferred.push(file_path.clone());
+            }
+        }
+        for file_path in deferred {
+            let change = diff.files.remove(&file_path).unwrap();
+            self.deferred.extend(change.renamed_from().cloned());
+            self.deferred.insert(file_path);
+        }
+    }
+
+    /// Sends the changes deferred by [`Self::defer_over_limits`], as far as they still
+    /// differ from what the other node has.
+    fn send_deferred_changes(
+        &mut self,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        if self.deferred.is_empty() {
+            return Ok(None);
+        }
+        let deferred = std::mem::take(&mut self.deferred);
+        let mut diff = self.changes_for_other();
+        diff.files
+            .retain(|file_path, _| deferred.contains(file_path));
+        self.changes_message(diff, content_store)
+    }
+
+    /// Chunks of the oldest upload that fit in the window. Called after every message,
+    /// so uploads advance as chunks are acknowledged.
+    pub fn next_chunks(&mut self, content_store: &ContentStore) -> Vec<NodeMessage> {
+        let mut chunks = Vec::new();
+        while let Some(upload) = self.uploads.front_mut() {
+            match upload.next_chunk(content_store) {
+                Ok(Some(chunk)) => chunks.push(chunk),
+                Ok(None) => break,
+                Err(e) => {
+                    // the content changed since, its new version has its own upload
+                    warn!(hash = %upload.hash, "Stopped streaming content: {e:#}");
+                    self.uploads.pop_front();
+                }
+            }
+        }
+        chunks
+    }
+
+    /// Handles the messages of a chunked transfer, for both sides.
+    fn handle_chunk_message(
+        &mut self,
+        message: NodeMessage,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        match message {
+            NodeMessage::Chunk {
+                hash,
+                size,
+                offset,
+                data,
+            } => Ok(Some(
+                match content_store.receive_chunk(hash, size, offset, &data)? {
+                    Some(received) => NodeMessage::ChunkAck { hash, received },
+                    None => NodeMessage::ChunkRejected { hash },
+                },
+            )),
+            NodeMessage::ChunkAck { hash, received } => {
+                let Some(upload) = self.uploads.iter_mut().find(|u| u.hash == hash) else {
+                    return Ok(None);
+                };
+                if !upload.acked(received) {
+                    return Ok(None);
+                }
+                self.uploads.retain(|upload| upload.hash != hash);
+                content_store.seen_from_other(&hash);
+                self.send_held_back_changes(hash, content_store)
+            }
+            NodeMessage::ChunkRejected { hash } => {
+                warn!(%hash, "Other node didn't receive the content intact, sending it again");
+                self.uploads.retain(|upload| upload.hash != hash);
+                self.send_held_back_changes(hash, content_store)
+            }
+            _ => unreachable!("not a chunk message"),
+        }
+    }
 
-        Ok(NodeMessage::Changes { content_diff, diff })
+    /// Sends the changes that were held back for the upload of `hash`. If the upload
+    /// failed, the content is still new to the other node and streamed again.
+    fn send_held_back_changes(
+        &mut self,
+        hash: ContentHash,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        let mut diff = self.changes_for_other();
+        diff.files
+            .retain(|_, change| change.new_content_hash() == Some(hash));
+        self.changes_message(diff, content_store)
     }
 
     pub fn handle_message_disk(
         &mut self,
         message: NodeMessage,
         root: &Path,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
     ) -> Result<Option<NodeMessage>> {
+        message.validate_paths()?;
+        self.check_limits(&message, &content_store.limits)?;
         match message {
-            NodeMessage::Changes { content_diff, diff } => {
-                content_store.apply_content_diff_from_other(&content_diff)?;
-                let accepted_diff =
-                    self.apply_changes_from_other_to_disk(&diff, root, content_store)?;
-                Ok(Some(NodeMessage::ChangesResponse { accepted_diff }))
+            NodeMessage::Changes {
+                content_diff,
+                diff,
+                mtimes,
+            } => {
+                let (diff, missing_content) =
+                    self.receive_changes(&content_diff, diff, content_store)?;
+                let accepted_diff = self.apply_changes_from_other_to_disk(
+                    &diff,
+                    &mtimes,
+                    root,
+                    options,
+                    content_store,
+                )?;
+                Ok(Some(NodeMessage::ChangesResponse {
+                    accepted_diff,
+                    missing_content,
+                }))
             }
-            NodeMessage::ChangesResponse { accepted_diff } => {
+            NodeMessage::ChangesResponse {
+                accepted_diff,
+                missing_content,
+            } => {
                 self.changes_acked_by_other(&accepted_diff);
-                Ok(None)
+                match self.resend_missing_content(&missing_content, content_store)? {
+                    Some(message) => Ok(Some(message)),
+                    None => self.send_deferred_changes(content_store),
+                }
             }
+            message => self.handle_chunk_message(message, content_store),
         }
     }
 
@@ -632,17 +2447,129 @@ impl Node {
         message: NodeMessage,
         content_store: &mut ContentStore,
     ) -> Result<Option<NodeMessage>> {
+        message.validate_paths()?;
+        self.check_limits(&message, &content_store.limits)?;
         match message {
-            NodeMessage::Changes { content_diff, diff } => {
-                content_store.apply_content_diff_from_other(&content_diff)?;
+            NodeMessage::Changes {
+                content_diff, diff, ..
+            } => {
+                let (diff, missing_content) =
+                    self.receive_changes(&content_diff, diff, content_store)?;
                 let accepted_diff = self.apply_changes_from_other_mem(&diff);
-                Ok(Some(NodeMessage::ChangesResponse { accepted_diff }))
+                Ok(Some(NodeMessage::ChangesResponse {
+                    accepted_diff,
+                    missing_content,
+                }))
             }
-            NodeMessage::ChangesResponse { accepted_diff } => {
+            NodeMessage::ChangesResponse {
+                accepted_diff,
+                missing_content,
+            } => {
                 self.changes_acked_by_other(&accepted_diff);
-                Ok(None)
+                match self.resend_missing_content(&missing_content, content_store)? {
+                    Some(message) => Ok(Some(message)),
+                    None => self.send_deferred_changes(content_store),
+                }
+            }
+            message => self.handle_chunk_message(message, content_store),
+        }
+    }
+
+    /// Rejects a message beyond the [`Limits`] of this node, before any of it is used.
+    fn check_limits(&self, message: &NodeMessage, limits: &Limits) -> Result<()> {
+        match message {
+            NodeMessage::Changes { diff, mtimes, .. } => {
+                limits.check_diff_files(diff.files.len().max(mtimes.len()))?;
+                let created = diff
+                    .files
+                    .iter()
+                    .filter(|(file_path, change)| {
+                        change.new_meta().is_some()
+                            && !self.other_state.files.contains_key(*file_path)
+                    })
+                    .count();
+                limits.check_tree_files(self.other_state.files.len() + created)
+            }
+            NodeMessage::ChangesResponse {
+                accepted_diff,
+                missing_content,
+            } => limits.check_diff_files(accepted_diff.files.len().max(missing_content.len())),
+            NodeMessage::Chunk { size, .. } => limits.check_file_bytes(*size),
+            NodeMessage::ChunkAck { .. } | NodeMessage::ChunkRejected { .. } => Ok(()),
+        }
+    }
+
+    /// Stores the contents of incoming changes, and splits off the changes whose content
+    /// didn't arrive intact. Those only update `other_state`, and their content is
+    /// requested again.
+    fn receive_changes(
+        &mut self,
+        content_diff: &ContentDiff,
+        mut diff: FsStateDiff,
+        content_store: &mut ContentStore,
+    ) -> Result<(FsStateDiff, Vec<ContentHash>)> {
+        content_store.apply_content_diff_from_other(content_diff)?;
+        let missing = diff.take_missing_content(content_store);
+        if missing.is_empty() {
+            return Ok((diff, Vec::new()));
+        }
+        error!(
+            paths = ?missing.files.keys().collect::<Vec<_>>(),
+            "Content didn't arrive intact, requesting it again"
+        );
+        self.other_state.set_from_diff(&missing);
+        let mut hashes: Vec<_> = missing
+            .files
+            .values()
+            .filter_map(FileChange::new_content_hash)
+            .collect();
+        hashes.sort_unstable_by_key(|hash| *hash.as_bytes());
+        hashes.dedup();
+        Ok((diff, hashes))
+    }
+
+    /// Sends the changes using `missing_content` again, with their full content.
+    fn resend_missing_content(
+        &mut self,
+        missing_content: &[ContentHash],
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        let mut diff = self.changes_for_other();
+        diff.files.retain(|_, change| {
+            change
+                .new_content_hash()
+                .is_some_and(|hash| missing_content.contains(&hash))
+        });
+        if diff.is_empty() {
+            return Ok(None);
+        }
+        warn!(
+            paths = ?diff.files.keys().collect::<Vec<_>>(),
+            "Other node didn't receive the content intact, sending it again"
+        );
+        content_store.new_contents.extend(missing_content);
+        self.hold_back_large_content(&mut diff, content_store)?;
+        if diff.is_empty() {
+            return Ok(None);
+        }
+        let mut content_diff = ContentDiff::new();
+        for hash in missing_content {
+            if self.uploads.iter().any(|upload| upload.hash == *hash) {
+                continue;
+            }
+            content_store.new_contents.remove(hash);
+            // changed since, the newer content is sent with the newer change
+            if let Ok(content) = content_store.get(hash) {
+                content_diff.add_new_content(*hash, content.to_vec());
             }
         }
+        let mtimes = content_store.mtimes(&diff);
+        self.in_flight.push_back(diff.clone());
+        Ok(Some(NodeMessage::Changes {
+            content_diff,
+            diff,
+            mtimes,
+        }))
     }
 
     pub fn changes_for_other(&mut self) -> FsStateDiff {
@@ -650,6 +2577,8 @@ impl Node {
     }
 
     pub fn changes_acked_by_other(&mut self, diff: &FsStateDiff) {
+        // responses arrive in the order the changes were sent
+        self.in_flight.pop_front();
         let conflicts = self.other_state.apply_diff(diff);
         if !conflicts.is_empty() {
             error!("Unexpected conflicts in acked changes");
@@ -657,11 +2586,8 @@ impl Node {
     }
 
     pub fn apply_changes_from_other_mem(&mut self, diff: &FsStateDiff) -> FsStateDiff {
-        let conflicts = self.other_state.apply_diff(diff);
-        if !conflicts.is_empty() {
-            error!("Unexpected conflicts in other state");
-        }
-        let conflicts = self.this_state.apply_diff(&diff);
+        self.other_state.set_from_diff(diff);
+        let conflicts = self.this_state.apply_diff(diff);
         let accepted_diff = FsStateDiff {
             files: diff
                 .files
@@ -670,39 +2596,229 @@ impl Node {
                 .map(|(k, v)| (k.clone(), v.clone()))
                 .collect(),
         };
-        self.conflicts.extend(conflicts);
+        self.forget_conflicts(&accepted_diff);
+        for file_path in conflicts {
+            let local = self.this_state.files.get(&file_path).cloned();
+            let change = &diff.files[&file_path];
+            self.add_conflict(Conflict::new(
+                file_path,
+                change,
+                local,
+                Resolution::KeptLocal,
+            ));
+        }
         accepted_diff
     }
 
+    /// Applies the changes of the other node, and resolves the ones that conflict with
+    /// local changes according to [`SyncOptions::conflict_policy`]. `mtimes` are the
+    /// modification times sent along with the changes.
     pub fn apply_changes_from_other_to_disk(
         &mut self,
         diff: &FsStateDiff,
+        mtimes: &BTreeMap<FilePath, i64>,
         root: &Path,
+        options: &SyncOptions,
         content_store: &mut ContentStore,
     ) -> anyhow::Result<FsStateDiff> {
-        let conflicts = self.other_state.apply_diff(diff);
-        if !conflicts.is_empty() {
-            error!("Unexpected conflicts in other state");
-        }
-        let conflicts = self
+        self.other_state.set_from_diff(diff);
+        let not_applied = self
             .this_state
-            .apply_diff_to_disk(diff, root, content_store)?;
-        let accepted_diff = FsStateDiff {
+            .apply_diff_to_disk(diff, root, options, content_store)?;
+        // untracked paths aren't accepted either, but aren't conflicts
+        let mut accepted_diff = FsStateDiff {
             files: diff
                 .files
                 .iter()
-                .filter(|(file_path, _change)| !conflicts.contains(file_path))
+                .filter(|(file_path, _change)| !not_applied.contains_key(*file_path))
                 .map(|(k, v)| (k.clone(), v.clone()))
                 .collect(),
         };
-        self.conflicts.extend(conflicts);
+        self.forget_conflicts(&accepted_diff);
+        let conflicts = not_applied
+            .into_iter()
+            .filter(|(_, outcome)| *outcome == Outcome::Conflict)
+            .map(|(file_path, _)| file_path);
+        for file_path in conflicts {
+            let change = &diff.files[&file_path];
+            // nothing is written there, not even a conflict copy
+            if !safe_fs::is_reachable(root, &file_path) {
+                self.remove_rename_source(
+                    change,
+                    &mut accepted_diff,
+                    root,
+                    options,
+                    content_store,
+                )?;
+                let conflict = Conflict::new(file_path, change, None, Resolution::KeptLocal);
+                self.add_conflict(conflict);
+                continue;
+            }
+            let local =
+                FileMetadata::from_fs(&file_path.to_absolute(root), options, content_store)?;
+            let resolution = conflict::resolve(
+                &mut self.this_state,
+                &file_path,
+                change,
+                mtimes.get(&file_path).copied(),
+                self.peer_host.as_deref().unwrap_or("remote"),
+                root,
+                options,
+                content_store,
+            )?;
+            // the other node's version was applied whole, source included
+            if resolution != Resolution::KeptRemote {
+                self.remove_rename_source(
+                    change,
+                    &mut accepted_diff,
+                    root,
+                    options,
+                    content_store,
+                )?;
+            }
+            match &resolution {
+                Resolution::KeptLocal => {
+                    warn!(path = ?file_path, "Conflict, kept the local version")
+                }
+                Resolution::KeptRemote => {
+                    warn!(path = ?file_path, "Conflict, kept the version of the other node");
+                    accepted_diff
+                        .files
+                        .insert(file_path.clone(), change.clone());
+                }
+                Resolution::KeptBoth { copy } => warn!(
+                    path = ?file_path,
+                    ?copy,
+                    "Conflict, wrote the version of the other node to a copy"
+                ),
+                Resolution::Merged => {
+                    info!(path = ?file_path, "Merged concurrent edits");
+                    continue;
+                }
+                Resolution::MergedWithMarkers => {
+                    warn!(path = ?file_path, "Conflict, merged with conflict markers")
+                }
+            }
+            self.add_conflict(Conflict::new(file_path, change, local, resolution));
+        }
         Ok(accepted_diff)
     }
 
+    /// Removes the source of a rename that conflicted where it leads, like the other node
+    /// did, and accepts the removal. Only the destination is a conflict.
+    fn remove_rename_source(
+        &mut self,
+        change: &FileChange,
+        accepted_diff: &mut FsStateDiff,
+        root: &Path,
+        options: &SyncOptions,
+        content_store: &mut ContentStore,
+    ) -> Result<()> {
+        let FileChange::Renamed { from, meta } = change else {
+            return Ok(());
+        };
+        let removal = FileChange::Removed {
+            old_meta: meta.clone(),
+        };
+        let outcome =
+            self.this_state
+                .apply_change_to_disk(from, &removal, root, options, content_store)?;
+        if outcome == Outcome::Applied {
+            accepted_diff.files.entry(from.clone()).or_insert(removal);
+        }
+        Ok(())
+    }
+
+    /// Forgets the conflicts on paths the other node changed since without a conflict,
+    /// e.g. when the conflict was resolved there.
+    fn forget_conflicts(&mut self, accepted_diff: &FsStateDiff) {
+        self.conflicts
+            .retain(|conflict| !accepted_diff.files.contains_key(&conflict.path));
+    }
+
+    /// Resolves the conflict on `file_path` with `choice`, and returns the change that
+    /// brings the other node to the same version.
+    pub fn resolve_conflict(
+        &mut self,
+        file_path: &FilePath,
+        choice: &ConflictChoice,
+        root: &Path,
+        options: &SyncOptions,
+        content_store: &mut ContentStore,
+    ) -> Result<Option<NodeMessage>> {
+        let Some(position) = self.conflicts.iter().position(|c| c.path == *file_path) else {
+            bail!("{file_path:?} has no conflict");
+        };
+        let full_path = file_path.to_absolute(root);
+        match choice {
+            ConflictChoice::Ours => {}
+            ConflictChoice::Theirs => {
+                let remote = self.conflicts[position].remote.clone();
+                let current = FileMetadata::from_fs(&full_path, options, content_store)?;
+                if let Some(change) = FileChange::between(current.as_ref(), remote.as_ref()) {
+                    let outcome = self.this_state.apply_change_to_disk(
+                        file_path,
+                        &change,
+                        root,
+                        options,
+                        content_store,
+                    )?;
+                    if outcome != Outcome::Applied {
+                        bail!("Failed to write the version of the other node to {file_path:?}");
+                    }
+                }
+            }
+            ConflictChoice::File(path) => {
+                let content = std::fs::read(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let entry = safe_fs::open_entry(root, file_path, true)?;
+                let mode = entry.file_mode().unwrap_or(DEFAULT_FILE_MODE);
+                entry.write_file(&content, mode, options.fsync)?;
+            }
+        }
+        let resolved = FileMetadata::from_fs(&full_path, options, content_store)?;
+        match &resolved {
+            Some(meta) => self
+                .this_state
+                .files
+                .insert(file_path.clone(), meta.clone()),
+            None => self.this_state.files.remove(file_path),
+        };
+        self.conflicts.remove(position);
+        info!(path = ?file_path, ?choice, "Resolved conflict");
+
+        let mut diff = self.since_sent([file_path.clone()]);
+        // sent even if the other node has this version, so it learns this node has it too
+        if diff.is_empty() {
+            let Some(meta) = resolved else {
+                return Ok(None);
+            };
+            let change = FileChange::Modified {
+                old_meta: meta.clone(),
+                new_meta: meta,
+            };
+            diff.files.insert(file_path.clone(), change);
+        }
+        self.send_diff(diff, content_store)
+    }
+
+    /// Records a conflict, replacing an earlier one on the same path.
+    fn add_conflict(&mut self, conflict: Conflict) {
+        self.conflicts.retain(|c| c.path != conflict.path);
+        self.conflicts.push(conflict);
+    }
+
+    /// Unresolved conflicts with changes of the other node, oldest first. Those of an
+    /// earlier run are restored from the cache directory.
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
     pub fn refresh_requests(
         &mut self,
         root: &Path,
         requests: &[RefreshRequest],
+        options: &SyncOptions,
         content_store: &mut ContentStore,
     ) -> Result<Option<NodeMessage>> {
         let mut diff = FsStateDiff {
@@ -713,24 +2829,67 @@ impl Node {
                 RefreshRequest::FullRescan(path) => {
                     let dir_diff =
                         self.this_state
-                            .refresh_full_rescan(root, path, content_store)?;
+                            .refresh_full_rescan(root, path, options, content_store)?;
                     diff.files.extend(dir_diff.files);
                 }
                 RefreshRequest::Path(path) => {
                     if let Some((file_path, change)) =
-                        self.this_state.refresh_path(root, path, content_store)?
+                        self.this_state
+                            .refresh_path(root, path, options, content_store)?
                     {
                         diff.files.insert(file_path, change);
                     }
                 }
             }
         }
-        if diff.is_empty() {
-            return Ok(None);
+        // stale changes are sent again, even if their files turn out unchanged
+        let stale = std::mem::take(&mut self.stale);
+        if !stale.is_empty() {
+            for (file_path, change) in self.changes_for_other().files {
+                if stale.contains(&file_path) {
+                    diff.files.entry(file_path).or_insert(change);
+                }
+            }
+        }
+        self.changes_message(diff, content_store)
+    }
+
+    /// Requests to hash the files of stale changes again, see
+    /// [`ContentStore::create_content_diff`]. The changes are sent again by the next
+    /// [`Self::refresh_requests`].
+    pub fn stale_requests(&self, root: &Path) -> Vec<RefreshRequest> {
+        self.stale
+            .iter()
+            .map(|file_path| RefreshRequest::Path(file_path.to_absolute(root)))
+            .collect()
+    }
+
+    /// Persists the state to the cache directory, so a restart can skip rehashing and
+    /// a reconnect can resume from `other_state` instead of overriding either side.
+    /// Unresolved conflicts are kept too, and stay unresolved after a restart.
+    pub fn save_state(&self, root: &Path, content_store: &ContentStore) -> Result<()> {
+        StateCache::save(root, &self.this_state, content_store)?;
+        save_conflicts(root, &self.conflicts)?;
+        if let Some(peer) = &self.peer {
+            save_peer_state(root, peer, &self.other_state)?;
         }
+        Ok(())
+    }
 
-        let content_diff = content_store.create_content_diff(&diff)?;
-        Ok(Some(NodeMessage::Changes { content_diff, diff }))
+    /// Drops contents that neither node can refer to anymore. Anything in either state
+    /// or in unanswered changes is kept, since the other node may still send a diff
+    /// against it.
+    pub fn collect_garbage(&self, content_store: &mut ContentStore) -> Result<GcStats> {
+        let states = [&self.this_state, &self.other_state];
+        let in_flight = self.in_flight.iter().flat_map(|diff| diff.files.values());
+        let live = states
+            .into_iter()
+            .flat_map(|state| state.files.values())
+            .filter_map(FileMetadata::content_hash)
+            .chain(in_flight.flat_map(FileChange::content_hashes))
+            .chain(self.conflicts.iter().flat_map(Conflict::content_hashes))
+            .collect();
+        content_store.retain(&live)
     }
 
     pub fn has_conflicts(&self) -> bool {
@@ -742,49 +2901,118 @@ impl Node {
     }
 }
 
-// TODO: don't watch git ignored paths
-// so we get git ignored files in diffs :/
+/// How often the poll watcher scans the root.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Turns the paths a watcher reports into refresh requests, leaving out what isn't
+/// synced.
+#[derive(Clone)]
+struct EventFilter {
+    root: PathBuf,
+    cache_dir: PathBuf,
+    filter: Arc<IgnoreFilter>,
+}
+
+impl EventFilter {
+    /// `is_dir` if the paths are directories that were created or removed.
+    fn requests(
+        &self,
+        paths: impl IntoIterator<Item = PathBuf>,
+        is_dir: bool,
+    ) -> Vec<RefreshRequest> {
+        paths
+            .into_iter()
+            // our own in-flight writes and data
+            .filter(|path| !is_temp_file(path) && !path.starts_with(&self.cache_dir))
+            .filter_map(|path| {
+                // the rules both nodes agreed on stay until they connect again
+                if path == self.root.join(FYNCIGNORE_FILE) {
+                    info!("Changes to .fyncignore apply once the nodes reconnect");
+                }
+                if is_ignore_file(&path) {
+                    // what is ignored below this directory may have changed
+                    let dir = path.parent()?;
+                    self.filter.invalidate(dir);
+                    return Some(RefreshRequest::FullRescan(dir.to_path_buf()));
+                }
+                let path_is_dir =
+                    is_dir || std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir());
+                if self.filter.is_ignored(&self.root, &path, path_is_dir) {
+                    return None;
+                }
+                Some(if is_dir {
+                    RefreshRequest::FullRescan(path)
+                } else {
+                    RefreshRequest::Path(path)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Watches the root until it is dropped.
+pub struct RootWatcher {
+    _watcher: Box<dyn Send>,
+}
+
+/// Calls `handler` with the paths to refresh as they change, using the watcher
+/// [`SyncOptions::watcher`] picks.
 pub fn watch_root(
     root: &Path,
+    options: &SyncOptions,
     handler: impl Fn(Vec<RefreshRequest>) + Send + 'static,
-) -> Result<RecommendedWatcher> {
-    let mut watcher = notify::RecommendedWatcher::new(
-        move |result: Result<notify::Event, _>| {
-            let Ok(event) = result else {
-                error!("Error in file watcher");
-                return;
-            };
-            let is_dir = match event.kind {
-                notify::EventKind::Any => false,
-                notify::EventKind::Access(_) => return,
-                notify::EventKind::Create(x) => x == CreateKind::Folder,
-                notify::EventKind::Modify(_) => false,
-                notify::EventKind::Remove(x) => x == RemoveKind::Folder,
-                notify::EventKind::Other => false,
-            };
-            let requests: Vec<RefreshRequest> = event
-                .paths
-                .into_iter()
-                .map(if is_dir {
-                    RefreshRequest::FullRescan
-                } else {
-                    RefreshRequest::Path
-                })
-                .collect();
-            handler(requests);
-        },
-        Default::default(),
-    )?;
+) -> Result<RootWatcher> {
+    let events = EventFilter {
+        root: root.to_path_buf(),
+        cache_dir: cache_dir(root),
+        filter: options.ignore_filter.clone(),
+    };
+    if options.watcher == WatcherKind::Fanotify {
+        match FanotifyGroup::new(root) {
+            Ok(group) => {
+                info!("Watching the filesystem of the root with fanotify");
+                return Ok(RootWatcher {
+                    _watcher: Box::new(group.watch(events, handler)),
+                });
+            }
+            Err(e) => warn!("Can't use fanotify, watching with inotify instead: {e}"),
+        }
+    }
+    let event_handler = move |result: Result<notify::Event, _>| {
+        let Ok(event) = result else {
+            error!("Error in file watcher");
+            return;
+        };
+        let is_dir = match event.kind {
+            notify::EventKind::Any => false,
+            notify::EventKind::Access(_) => return,
+            notify::EventKind::Create(x) => x == CreateKind::Folder,
+            notify::EventKind::Modify(_) => false,
+            notify::EventKind::Remove(x) => x == RemoveKind::Folder,
+            notify::EventKind::Other => false,
+        };
+        handler(events.requests(event.paths, is_dir));
+    };
+    let mut watcher: Box<dyn Watcher + Send> = match options.watcher {
+        WatcherKind::Poll => Box::new(PollWatcher::new(
+            event_handler,
+            notify::Config::default().with_poll_interval(POLL_INTERVAL),
+        )?),
+        WatcherKind::Fanotify | WatcherKind::Inotify => {
+            Box::new(RecommendedWatcher::new(event_handler, Default::default())?)
+        }
+    };
 
     // TODO: check how this interacts with new directories
     // FIXME: this wasted effort by walking the tree *once again*
     watcher.watch(root, RecursiveMode::Recursive)?;
-    Ok(watcher)
+    Ok(RootWatcher { _watcher: watcher })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::VecDeque;
 
     #[allow(dead_code)]
     impl FsState {
@@ -795,8 +3023,9 @@ mod tests {
         }
         fn insert_file(&mut self, path: &str, content: ContentHash) {
             let file_path = FilePath(Arc::from(path));
-            let metadata = FileMetadata {
+            let metadata = FileMetadata::File {
                 content_hash: content,
+                mode: 0o644,
             };
             self.files.insert(file_path, metadata);
         }
@@ -804,14 +3033,31 @@ mod tests {
             let file_path = FilePath(Arc::from(path));
             self.files.remove(&file_path);
         }
+        fn set_mode(&mut self, path: &str, new_mode: u32) {
+            let file_path = FilePath(Arc::from(path));
+            if let Some(FileMetadata::File { mode, .. }) = self.files.get_mut(&file_path) {
+                *mode = new_mode;
+            }
+        }
+        fn insert_dir(&mut self, path: &str) {
+            let file_path = FilePath(Arc::from(path));
+            self.files.insert(file_path, FileMetadata::Directory);
+        }
+        fn insert_symlink(&mut self, path: &str, target: &str) {
+            let file_path = FilePath(Arc::from(path));
+            let metadata = FileMetadata::Symlink {
+                target: Arc::from(target),
+            };
+            self.files.insert(file_path, metadata);
+        }
     }
 
     #[test]
     fn test_fs_state() {
         // Create a new ContentStore and add two content hashes
         let mut cs = ContentStore::default();
-        let h1 = cs.add(b"hello world".to_vec());
-        let h2 = cs.add(b"bye world".to_vec());
+        let h1 = cs.add(b"hello world".to_vec()).unwrap();
+        let h2 = cs.add(b"bye world".to_vec()).unwrap();
 
         // Create and populate the first file state
         let mut state1 = FsState::empty();
@@ -848,8 +3094,8 @@ mod tests {
     fn test_node() {
         let mut cs = ContentStore::default();
 
-        let h1 = cs.add(b"hello world".to_vec());
-        let h2 = cs.add(b"bye world".to_vec());
+        let h1 = cs.add(b"hello world".to_vec()).unwrap();
+        let h2 = cs.add(b"bye world".to_vec()).unwrap();
 
         let mut state1 = FsState::empty();
         state1.insert_file("file1.txt", h1);
@@ -887,9 +3133,9 @@ mod tests {
     fn test_concurrent_changes() {
         let mut cs = ContentStore::default();
 
-        let h1 = cs.add(b"content1".to_vec());
-        let h2 = cs.add(b"content2".to_vec());
-        let h3 = cs.add(b"content3".to_vec());
+        let h1 = cs.add(b"content1".to_vec()).unwrap();
+        let h2 = cs.add(b"content2".to_vec()).unwrap();
+        let h3 = cs.add(b"content3".to_vec()).unwrap();
 
         let mut state1 = FsState::empty();
         state1.insert_file("file1.txt", h1);
@@ -933,9 +3179,9 @@ mod tests {
     fn test_conflicting_changes() {
         let mut cs = ContentStore::default();
 
-        let h1 = cs.add(b"content1".to_vec());
-        let h2 = cs.add(b"content2".to_vec());
-        let h3 = cs.add(b"content3".to_vec());
+        let h1 = cs.add(b"content1".to_vec()).unwrap();
+        let h2 = cs.add(b"content2".to_vec()).unwrap();
+        let h3 = cs.add(b"content3".to_vec()).unwrap();
 
         let mut state1 = FsState::empty();
         state1.insert_file("file1.txt", h1);
@@ -968,16 +3214,22 @@ mod tests {
         assert!(!node2.is_settle());
 
         // Verify that the conflicted file is "file1.txt"
-        assert_eq!(node1.conflicts, vec![FilePath(Arc::from("file1.txt"))]);
-        assert_eq!(node2.conflicts, vec![FilePath(Arc::from("file1.txt"))]);
+        assert_eq!(
+            conflict_paths(&node1),
+            vec![FilePath(Arc::from("file1.txt"))]
+        );
+        assert_eq!(
+            conflict_paths(&node2),
+            vec![FilePath(Arc::from("file1.txt"))]
+        );
     }
 
     #[test]
     fn test_conflicting_concurrent_create() {
         let mut cs = ContentStore::default();
 
-        let h1 = cs.add(b"content1".to_vec());
-        let h2 = cs.add(b"content2".to_vec());
+        let h1 = cs.add(b"content1".to_vec()).unwrap();
+        let h2 = cs.add(b"content2".to_vec()).unwrap();
 
         let mut state1 = FsState::empty();
         state1.insert_file("file1.txt", h1);
@@ -1005,16 +3257,22 @@ mod tests {
         assert!(node2.has_conflicts());
 
         // Verify that the conflicted file is "file2.txt"
-        assert_eq!(node1.conflicts, vec![FilePath(Arc::from("file2.txt"))]);
-        assert_eq!(node2.conflicts, vec![FilePath(Arc::from("file2.txt"))]);
+        assert_eq!(
+            conflict_paths(&node1),
+            vec![FilePath(Arc::from("file2.txt"))]
+        );
+        assert_eq!(
+            conflict_paths(&node2),
+            vec![FilePath(Arc::from("file2.txt"))]
+        );
     }
 
     #[test]
     fn test_concurrent_create_same_file_same_content() {
         let mut cs = ContentStore::default();
 
-        let h1 = cs.add(b"content1".to_vec());
-        let h2 = cs.add(b"same_content".to_vec());
+        let h1 = cs.add(b"content1".to_vec()).unwrap();
+        let h2 = cs.add(b"same_content".to_vec()).unwrap();
 
         let mut state1 = FsState::empty();
         state1.insert_file("file1.txt", h1);
@@ -1051,4 +3309,1518 @@ mod tests {
         assert_eq!(node1.this_state, state3);
         assert_eq!(node1.this_state, state2);
     }
+
+    #[test]
+    fn test_mode_only_change() {
+        let mut cs = ContentStore::default();
+        let h1 = cs.add(b"#!/bin/sh".to_vec()).unwrap();
+
+        let mut state1 = FsState::empty();
+        state1.insert_file("build.sh", h1);
+        let mut state2 = state1.clone();
+        state2.set_mode("build.sh", 0o755);
+
+        let mut diff = state1.diff(&state2);
+        assert!(matches!(
+            diff.files.get(&FilePath(Arc::from("build.sh"))),
+            Some(FileChange::Modified {
+                old_meta: FileMetadata::File { mode: 0o644, .. },
+                new_meta: FileMetadata::File { mode: 0o755, .. },
+            })
+        ));
+
+        // content was already seen, so only the metadata is transferred
+        cs.seen_from_other(&h1);
+        let (content_diff, _) = cs.create_content_diff(&mut diff).unwrap();
+        assert!(content_diff.new_content.is_empty());
+        assert!(content_diff.modified_content.is_empty());
+    }
+
+    #[test]
+    fn test_mode_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let script = root.join("build.sh");
+        std::fs::write(&script, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut cs = ContentStore::default();
+        let options = SyncOptions::default();
+        let mut state = FsState::from_disk(root, &options, &mut cs).unwrap();
+        let mut next = state.clone();
+        next.set_mode("build.sh", 0o755);
+
+        let diff = state.diff(&next);
+        let conflicts = state
+            .apply_diff_to_disk(&diff, root, &options, &mut cs)
+            .unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(state, next);
+        let mode = std::fs::metadata(&script).unwrap().permissions().mode();
+        assert_eq!(mode & PERMISSION_BITS, 0o755);
+
+        let mut rescanned = FsState::from_disk(root, &options, &mut cs).unwrap();
+        assert_eq!(rescanned, next);
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let change = rescanned
+            .refresh_path(root, &script, &options, &mut cs)
+            .unwrap();
+        assert!(matches!(
+            change,
+            Some((
+                _,
+                FileChange::Modified {
+                    new_meta: FileMetadata::File { mode: 0o700, .. },
+                    ..
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_symlink_policy() {
+        let link = FilePath(Arc::from("a/link"));
+        assert!(SymlinkPolicy::Allow.allows(&link, "/etc/passwd"));
+        assert!(!SymlinkPolicy::RejectAbsolute.allows(&link, "/etc/passwd"));
+        assert!(SymlinkPolicy::RejectAbsolute.allows(&link, "../../outside"));
+        assert!(SymlinkPolicy::WithinRoot.allows(&link, "../b/c"));
+        assert!(SymlinkPolicy::WithinRoot.allows(&link, "./x/../../b"));
+        assert!(!SymlinkPolicy::WithinRoot.allows(&link, "../../outside"));
+        assert!(!SymlinkPolicy::WithinRoot.allows(&link, "/etc/passwd"));
+    }
+
+    #[test]
+    fn test_hostile_paths_stay_inside_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+        let options = SyncOptions::default();
+        let mut cs = ContentStore::default();
+        let empty = FsState {
+            files: BTreeMap::new(),
+        };
+        let mut node = Node::new(FsState::from_disk(&root, &options, &mut cs).unwrap(), empty);
+        let key = b"ssh-ed25519 AAAA";
+        let meta = FileMetadata::File {
+            content_hash: blake3::hash(key),
+            mode: 0o644,
+        };
+        let mut send = |changes: Vec<(&str, FileChange)>| {
+            let mut sender = ContentStore::default();
+            sender.add(key.to_vec()).unwrap();
+            let mut diff = FsStateDiff {
+                files: changes
+                    .into_iter()
+                    .map(|(path, change)| (FilePath(path.into()), change))
+                    .collect(),
+            };
+            let message = NodeMessage::Changes {
+                content_diff: sender.create_content_diff(&mut diff).unwrap().0,
+                diff,
+                mtimes: BTreeMap::new(),
+            };
+            node.handle_message_disk(message, &root, &options, &mut cs)
+        };
+        let created = || FileChange::Created { meta: meta.clone() };
+
+        for path in [
+            "../outside/authorized_keys",
+            "/tmp/authorized_keys",
+            "a/../../outside/authorized_keys",
+            ".fync/id",
+            "a//b",
+            "./a",
+            "a\0b",
+            "",
+        ] {
+            assert!(send(vec![(path, created())]).is_err(), "accepted {path:?}");
+        }
+        let renamed = FileChange::Renamed {
+            from: FilePath("../outside/secret".into()),
+            meta: meta.clone(),
+        };
+        assert!(send(vec![("stolen", renamed)]).is_err());
+
+        // a valid path through a symlink is a conflict, nothing is written
+        send(vec![("link/authorized_keys", created())]).unwrap();
+        assert_eq!(
+            conflict_paths(&node),
+            vec![FilePath("link/authorized_keys".into())]
+        );
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+        assert!(!dir.path().join("authorized_keys").exists());
+        assert!(!root.join(CACHE_DIR).exists());
+    }
+
+    #[test]
+    fn test_symlinks_on_disk() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("releases")).unwrap();
+        std::fs::write(src.path().join("releases/x"), b"x").unwrap();
+        std::os::unix::fs::symlink("releases/x", src.path().join("current")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", src.path().join("passwd")).unwrap();
+
+        let mut cs = ContentStore::default();
+        let options = SyncOptions::default();
+        let src_state = FsState::from_disk(src.path(), &options, &mut cs).unwrap();
+        let mut expected = FsState::empty();
+        expected.insert_symlink("current", "releases/x");
+        expected.insert_symlink("passwd", "/etc/passwd");
+        expected.insert_dir("releases");
+        expected.insert_file("releases/x", blake3::hash(b"x"));
+        assert_eq!(src_state, expected);
+
+        let strict = SyncOptions {
+            symlink_policy: SymlinkPolicy::WithinRoot,
+            ..Default::default()
+        };
+        let mut dst_state = FsState::empty();
+        let diff = dst_state.diff(&src_state);
+        let not_applied = dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &strict, &mut cs)
+            .unwrap();
+        assert_eq!(
+            not_applied,
+            BTreeMap::from([(FilePath(Arc::from("passwd")), Outcome::NotTracked)])
+        );
+        assert!(!path_exists(&dst.path().join("passwd")));
+        assert_eq!(
+            std::fs::read_link(dst.path().join("current")).unwrap(),
+            Path::new("releases/x")
+        );
+
+        // retargeting the link is a modification
+        let mut next = dst_state.clone();
+        next.insert_symlink("current", "releases/y");
+        let diff = dst_state.diff(&next);
+        assert!(matches!(
+            diff.files.get(&FilePath(Arc::from("current"))),
+            Some(FileChange::Modified { .. })
+        ));
+        dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &strict, &mut cs)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_link(dst.path().join("current")).unwrap(),
+            Path::new("releases/y")
+        );
+    }
+
+    #[test]
+    fn test_directories_on_disk() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(src.path().join("migrations")).unwrap();
+        std::fs::create_dir_all(src.path().join("old/nested")).unwrap();
+        std::fs::write(src.path().join("old/nested/a"), b"a").unwrap();
+
+        let mut cs = ContentStore::default();
+        let options = SyncOptions::default();
+        let mut src_state = FsState::from_disk(src.path(), &options, &mut cs).unwrap();
+        let mut expected = FsState::empty();
+        expected.insert_dir("migrations");
+        expected.insert_dir("old");
+        expected.insert_dir("old/nested");
+        expected.insert_file("old/nested/a", blake3::hash(b"a"));
+        assert_eq!(src_state, expected);
+
+        let mut dst_state = FsState::empty();
+        let diff = dst_state.diff(&src_state);
+        let conflicts = dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &options, &mut cs)
+            .unwrap();
+        assert!(conflicts.is_empty());
+        assert!(dst.path().join("migrations").is_dir());
+        assert!(dst.path().join("old/nested/a").is_file());
+
+        // removing the whole tree removes directories on the other side too
+        std::fs::remove_dir_all(src.path().join("old")).unwrap();
+        let diff = src_state
+            .refresh_full_rescan(src.path(), Path::new("old"), &options, &mut cs)
+            .unwrap();
+        assert_eq!(diff.files.len(), 3);
+        let conflicts = dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &options, &mut cs)
+            .unwrap();
+        assert!(conflicts.is_empty());
+        assert!(!path_exists(&dst.path().join("old")));
+        assert_eq!(dst_state, src_state);
+    }
+
+    #[test]
+    fn test_prune_untracked_parent() {
+        let dst = tempfile::tempdir().unwrap();
+        let mut cs = ContentStore::default();
+        let options = SyncOptions::default();
+        let h1 = cs.add(b"x".to_vec()).unwrap();
+
+        // the parent isn't tracked, e.g. it was created implicitly for the file
+        let mut dst_state = FsState::empty();
+        let mut next = FsState::empty();
+        next.insert_file("p/q/x", h1);
+        let diff = dst_state.diff(&next);
+        dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &options, &mut cs)
+            .unwrap();
+        assert!(dst.path().join("p/q/x").is_file());
+
+        let diff = dst_state.diff(&FsState::empty());
+        dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &options, &mut cs)
+            .unwrap();
+        assert!(!path_exists(&dst.path().join("p")));
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        let dst = tempfile::tempdir().unwrap();
+        let mut cs = ContentStore::default();
+        let options = SyncOptions {
+            fsync: true,
+            ..Default::default()
+        };
+        let h1 = cs.add(b"old".to_vec()).unwrap();
+        let h2 = cs.add(b"new".to_vec()).unwrap();
+
+        let mut dst_state = FsState::empty();
+        let mut next = FsState::empty();
+        next.insert_file("a.txt", h1);
+        let diff = dst_state.diff(&next);
+        dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &options, &mut cs)
+            .unwrap();
+        let mut next = dst_state.clone();
+        next.insert_file("a.txt", h2);
+        let diff = dst_state.diff(&next);
+        dst_state
+            .apply_diff_to_disk(&diff, dst.path(), &options, &mut cs)
+            .unwrap();
+
+        assert_eq!(std::fs::read(dst.path().join("a.txt")).unwrap(), b"new");
+        let mode = std::fs::metadata(dst.path().join("a.txt"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & PERMISSION_BITS, 0o644);
+        // no temp files are left behind
+        let names: Vec<_> = std::fs::read_dir(dst.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["a.txt"]);
+        assert!(is_temp_file(Path::new("/x/.fync-tmp-abc")));
+        assert!(!is_temp_file(Path::new("/x/a.txt")));
+    }
+
+    #[test]
+    fn test_gitignore_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("target/out"), b"out").unwrap();
+        std::fs::write(root.join("build.log"), b"log").unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+
+        let mut cs = ContentStore::default();
+        let options = SyncOptions::default();
+        let mut state = FsState::from_disk(root, &options, &mut cs).unwrap();
+        let mut expected = FsState::empty();
+        expected.insert_file("build.log", blake3::hash(b"log"));
+        assert_eq!(state, expected);
+
+        // events for ignored paths don't change anything
+        std::fs::write(root.join("target/out"), b"out2").unwrap();
+        let change = state
+            .refresh_path(root, &root.join("target/out"), &options, &mut cs)
+            .unwrap();
+        assert!(change.is_none());
+
+        // editing the rules re-evaluates the directory
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        options.ignore_filter.invalidate(root);
+        let diff = state
+            .refresh_full_rescan(root, root, &options, &mut cs)
+            .unwrap();
+        assert!(matches!(
+            diff.files.get(&FilePath("build.log".into())),
+            Some(FileChange::Removed { .. })
+        ));
+        assert!(matches!(
+            diff.files.get(&FilePath("target/out".into())),
+            Some(FileChange::Created { .. })
+        ));
+
+        // changes from the other side to ignored paths aren't written
+        let mut next = state.clone();
+        next.insert_file("other.log", blake3::hash(b"log"));
+        let not_applied = state
+            .apply_diff_to_disk(&state.diff(&next), root, &options, &mut cs)
+            .unwrap();
+        assert_eq!(
+            not_applied,
+            BTreeMap::from([(FilePath("other.log".into()), Outcome::NotTracked)])
+        );
+        assert!(!path_exists(&root.join("other.log")));
+    }
+
+    #[test]
+    fn test_ignored_changes_from_other() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        std::fs::write(a.path().join("secrets.env"), b"KEY=1").unwrap();
+        std::fs::create_dir_all(b.path().join(".git/info")).unwrap();
+        std::fs::write(b.path().join(".git/info/exclude"), "secrets.env\n").unwrap();
+
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+        assert!(!path_exists(&b.path().join("secrets.env")));
+
+        // neither accepted nor a conflict, so no conflict copy is written
+        std::fs::write(a.path().join("secrets.env"), b"KEY=2").unwrap();
+        let requests = [RefreshRequest::Path(a.path().join("secrets.env"))];
+        let message = nodes[0]
+            .refresh_requests(a.path(), &requests, &SyncOptions::default(), &mut stores[0])
+            .unwrap()
+            .unwrap();
+        let response = nodes[1]
+            .handle_message_disk(message, b.path(), &SyncOptions::default(), &mut stores[1])
+            .unwrap();
+        let Some(NodeMessage::ChangesResponse { accepted_diff, .. }) = response else {
+            panic!("expected a response, got {response:?}");
+        };
+        assert!(accepted_diff.is_empty());
+        assert!(!nodes[1].has_conflicts());
+        let copies = std::fs::read_dir(b.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("secrets")
+            })
+            .count();
+        assert_eq!(copies, 0);
+    }
+
+    #[test]
+    fn test_features_turned_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("run.sh"), b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(root.join("run.sh"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::os::unix::fs::symlink("run.sh", root.join("link")).unwrap();
+
+        let mut cs = ContentStore::default();
+        let options = SyncOptions {
+            features: Features::ALL
+                .without(Features::MODES)
+                .without(Features::SYMLINKS),
+            ..Default::default()
+        };
+        let mut state = FsState::from_disk(root, &options, &mut cs).unwrap();
+        let mut expected = FsState::empty();
+        expected.insert_file("run.sh", blake3::hash(b"#!/bin/sh"));
+        assert_eq!(state, expected);
+
+        // overwriting keeps the mode on disk
+        let mut next = state.clone();
+        next.insert_file("run.sh", cs.add(b"#!/bin/bash".to_vec()).unwrap());
+        let conflicts = state
+            .apply_diff_to_disk(&state.diff(&next), root, &options, &mut cs)
+            .unwrap();
+        assert!(conflicts.is_empty());
+        let mode = std::fs::metadata(root.join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & PERMISSION_BITS, 0o755);
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut cs1 = ContentStore::default();
+        let mut cs2 = ContentStore::default();
+        let h1 = cs1.add(b"v1".to_vec()).unwrap();
+        let h2 = cs1.add(b"v2".to_vec()).unwrap();
+        let h3 = cs1.add(b"unused".to_vec()).unwrap();
+        cs2.add(b"v1".to_vec()).unwrap();
+        cs1.seen_from_other(&h1);
+
+        let mut state = FsState::empty();
+        state.insert_file("a", h1);
+        let mut node1 = Node::new(state.clone(), state.clone());
+        let mut node2 = Node::new(state.clone(), state);
+        node1.this_state.insert_file("a", h2);
+        let message = node1.messages_for_other(&mut cs1).unwrap().unwrap();
+
+        // the old version is still needed until the other node responds
+        let stats = node1.collect_garbage(&mut cs1).unwrap();
+        assert_eq!(stats.removed_blobs, 1);
+        assert!(!cs1.has(&h3));
+        assert!(cs1.has(&h1));
+
+        let response = node2
+            .handle_message_mem(message, &mut cs2)
+            .unwrap()
+            .unwrap();
+        node1.handle_message_mem(response, &mut cs1).unwrap();
+        let stats = node1.collect_garbage(&mut cs1).unwrap();
+        assert_eq!(stats.removed_blobs, 1);
+        assert_eq!(stats.remaining_blobs, 1);
+        assert!(!cs1.has(&h1));
+        let stats = node2.collect_garbage(&mut cs2).unwrap();
+        assert_eq!(stats.removed_blobs, 1);
+        assert_eq!(&*cs2.get(&h2).unwrap(), b"v2");
+    }
+
+    #[test]
+    fn test_corrupted_content() {
+        let mut cs1 = ContentStore::default();
+        let mut cs2 = ContentStore::default();
+        let hash = cs1.add(b"hello".to_vec()).unwrap();
+        let mut node1 = Node::new(FsState::empty(), FsState::empty());
+        let mut node2 = Node::new(FsState::empty(), FsState::empty());
+        node1.this_state.insert_file("a", hash);
+        let mut message = node1.messages_for_other(&mut cs1).unwrap().unwrap();
+        let NodeMessage::Changes { content_diff, .. } = &mut message else {
+            unreachable!()
+        };
+        content_diff.new_content[0].data[0] ^= 1;
+
+        let response = node2
+            .handle_message_mem(message, &mut cs2)
+            .unwrap()
+            .unwrap();
+        let NodeMessage::ChangesResponse {
+            accepted_diff,
+            missing_content,
+        } = &response
+        else {
+            unreachable!()
+        };
+        assert!(accepted_diff.is_empty());
+        assert_eq!(missing_content, &[hash]);
+        assert!(node2.this_state.files.is_empty());
+        assert!(node2.conflicts.is_empty());
+
+        let resent = node1
+            .handle_message_mem(response, &mut cs1)
+            .unwrap()
+            .unwrap();
+        let response = node2.handle_message_mem(resent, &mut cs2).unwrap().unwrap();
+        assert!(node1
+            .handle_message_mem(response, &mut cs1)
+            .unwrap()
+            .is_none());
+        assert_eq!(node2.this_state, node1.this_state);
+        assert_eq!(&*cs2.get(&hash).unwrap(), b"hello");
+        assert!(node1.is_settle());
+    }
+
+    #[test]
+    fn test_corrupted_delta() {
+        let mut cs1 = ContentStore::default();
+        let mut cs2 = ContentStore::default();
+        let old = cs1.add(b"hello world".repeat(100)).unwrap();
+        let new = cs1.add(b"hello there".repeat(100)).unwrap();
+        cs2.add(b"hello world".repeat(100)).unwrap();
+        cs1.seen_from_other(&old);
+
+        let mut state = FsState::empty();
+        state.insert_file("a", old);
+        let mut node1 = Node::new(state.clone(), state.clone());
+        let mut node2 = Node::new(state.clone(), state);
+        node1.this_state.insert_file("a", new);
+        let mut message = node1.messages_for_other(&mut cs1).unwrap().unwrap();
+        let NodeMessage::Changes { content_diff, .. } = &mut message else {
+            unreachable!()
+        };
+        content_diff.modified_content[0].data.truncate(4);
+
+        // the delta can't be decompressed, so the full content is requested
+        let response = node2
+            .handle_message_mem(message, &mut cs2)
+            .unwrap()
+            .unwrap();
+        let resent = node1
+            .handle_message_mem(response, &mut cs1)
+            .unwrap()
+            .unwrap();
+        let response = node2.handle_message_mem(resent, &mut cs2).unwrap().unwrap();
+        node1.handle_message_mem(response, &mut cs1).unwrap();
+        assert_eq!(node2.this_state, node1.this_state);
+        assert_eq!(&*cs2.get(&new).unwrap(), &b"hello there".repeat(100)[..]);
+        assert!(node1.is_settle());
+    }
+
+    #[test]
+    fn test_large_content_skips_delta() {
+        let old = vec![1; MAX_DELTA_BYTES + 1];
+        let mut new = old.clone();
+        new[0] = 2;
+        let (old_hash, new_hash) = (blake3::hash(&old), blake3::hash(&new));
+        let mut content_diff = ContentDiff::new();
+        content_diff
+            .add_modified_content(old_hash, &old, new_hash, &new)
+            .unwrap();
+        assert!(content_diff.modified_content.is_empty());
+        assert_eq!(content_diff.compressed_content.len(), 1);
+
+        let mut cs = ContentStore::default();
+        cs.apply_content_diff_from_other(&content_diff).unwrap();
+        assert_eq!(*cs.get(&new_hash).unwrap(), new);
+    }
+
+    #[test]
+    fn test_content_changed_while_sent() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        let options = SyncOptions::default();
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+        // hashed without keeping a copy, like files known from the state cache
+        let mut hashed = |name: &str, content: &[u8]| {
+            let path = a.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            let stat = FileStat::from_metadata(&std::fs::metadata(&path).unwrap());
+            let content_hash = blake3::hash(content);
+            stores[0].record_file(path, stat, content_hash);
+            let meta = FileMetadata::File {
+                content_hash,
+                mode: 0o644,
+            };
+            nodes[0]
+                .this_state
+                .files
+                .insert(FilePath(name.into()), meta);
+        };
+
+        // one of two identical files is gone, the content is read from the other
+        hashed("one", b"same");
+        hashed("two", b"same");
+        hashed("notes", b"v1");
+        std::fs::remove_file(a.path().join("two")).unwrap();
+        // the change of a file saved again since it was hashed is dropped
+        std::fs::write(a.path().join("notes"), b"v2").unwrap();
+        let message = nodes[0]
+            .messages_for_other(&mut stores[0])
+            .unwrap()
+            .unwrap();
+        let NodeMessage::Changes { diff, .. } = &message else {
+            panic!("expected changes, got {message:?}");
+        };
+        let paths: Vec<_> = diff.files.keys().map(|p| p.0.as_ref()).collect();
+        assert_eq!(paths, ["one", "two"]);
+        exchange(
+            &mut nodes,
+            roots,
+            &mut stores,
+            VecDeque::from([(1, message)]),
+        );
+        assert_eq!(std::fs::read(b.path().join("one")).unwrap(), b"same");
+
+        // and sent once the file is hashed again
+        let requests = nodes[0].stale_requests(a.path());
+        assert_eq!(requests, [RefreshRequest::Path(a.path().join("notes"))]);
+        let message = nodes[0]
+            .refresh_requests(a.path(), &requests, &options, &mut stores[0])
+            .unwrap()
+            .unwrap();
+        exchange(
+            &mut nodes,
+            roots,
+            &mut stores,
+            VecDeque::from([(1, message)]),
+        );
+        assert_eq!(std::fs::read(b.path().join("notes")).unwrap(), b"v2");
+        assert!(nodes[0].stale_requests(a.path()).is_empty());
+    }
+
+    /// Runs the init handshake between two roots, like `run_node` does over ssh.
+    fn init_nodes(roots: [&Path; 2], stores: &mut [ContentStore; 2]) -> [Node; 2] {
+        let options = [SyncOptions::default(), SyncOptions::default()];
+        let [cs0, cs1] = stores;
+        // the first node leads
+        let mut inits = [
+            NodeInit::from_disk(roots[0], &options[0], cs0, true).unwrap(),
+            NodeInit::from_disk(roots[1], &options[1], cs1, false).unwrap(),
+        ];
+        let mut nodes = [None, None];
+        let mut pending: VecDeque<_> = inits
+            .iter()
+            .enumerate()
+            .filter_map(|(i, init)| Some((1 - i, init.announce()?)))
+            .collect();
+        while let Some((to, message)) = pending.pop_front() {
+            let (node, response) = inits[to]
+                .handle_init_message(roots[to], message, &options[to], &mut stores[to])
+                .unwrap();
+            if let Some(node) = node {
+                nodes[to] = Some(node);
+            }
+            if let Some(response) = response {
+                pending.push_back((1 - to, response));
+            }
+        }
+        nodes.map(Option::unwrap)
+    }
+
+    fn conflict_paths(node: &Node) -> Vec<FilePath> {
+        node.conflicts.iter().map(|c| c.path.clone()).collect()
+    }
+
+    /// Delivers messages between two nodes until both are idle, streaming chunks the
+    /// way `run_node` does.
+    fn exchange(
+        nodes: &mut [Node; 2],
+        roots: [&Path; 2],
+        stores: &mut [ContentStore; 2],
+        mut pending: VecDeque<(usize, NodeMessage)>,
+    ) {
+        let options = SyncOptions::default();
+        for i in 0..2 {
+            let chunks = nodes[i].next_chunks(&stores[i]);
+            pending.extend(chunks.into_iter().map(|chunk| (1 - i, chunk)));
+        }
+        while let Some((to, message)) = pending.pop_front() {
+            if let Some(response) = nodes[to]
+                .handle_message_disk(message, roots[to], &options, &mut stores[to])
+                .unwrap()
+            {
+                pending.push_back((1 - to, response));
+            }
+            let chunks = nodes[to].next_chunks(&stores[to]);
+            pending.extend(chunks.into_iter().map(|chunk| (1 - to, chunk)));
+        }
+    }
+
+    /// Two synced roots where `name` started as `base`, then was edited to `edits[i]` in
+    /// each root. Neither node noticed its edit yet, see [`refresh_path`].
+    fn edited_on_both_sides(
+        name: &str,
+        base: &[u8],
+        edits: [&[u8]; 2],
+    ) -> ([tempfile::TempDir; 2], [ContentStore; 2], [Node; 2]) {
+        let dirs = [(); 2].map(|_| tempfile::tempdir().unwrap());
+        let roots = dirs.each_ref().map(|dir| dir.path());
+        std::fs::write(roots[0].join(name), base).unwrap();
+        // the modification times sent along come from stores that know their root
+        let mut stores =
+            roots.map(|root| ContentStore::on_disk(root, &SyncOptions::default()).unwrap());
+        let nodes = init_nodes(roots, &mut stores);
+        for (root, edit) in roots.into_iter().zip(edits) {
+            std::fs::write(root.join(name), edit).unwrap();
+        }
+        (dirs, stores, nodes)
+    }
+
+    /// The message a node sends after noticing that `name` changed.
+    fn refresh_path(
+        node: &mut Node,
+        root: &Path,
+        name: &str,
+        options: &SyncOptions,
+        store: &mut ContentStore,
+    ) -> Option<NodeMessage> {
+        let requests = [RefreshRequest::Path(root.join(name))];
+        node.refresh_requests(root, &requests, options, store)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sync_rules_negotiated() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        std::fs::write(a.path().join(".fyncignore"), "*.bin\n!.env.local\n").unwrap();
+        std::fs::write(a.path().join(".env.local"), b"KEY=1").unwrap();
+        std::fs::write(b.path().join("data.bin"), b"data").unwrap();
+
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let nodes = init_nodes(roots, &mut stores);
+        // b uses a's rules, so the override neither removes nor tracks data.bin
+        assert_eq!(
+            std::fs::read(b.path().join(".env.local")).unwrap(),
+            b"KEY=1"
+        );
+        assert_eq!(std::fs::read(b.path().join("data.bin")).unwrap(), b"data");
+        assert_eq!(nodes[0].this_state, nodes[1].this_state);
+    }
+
+    #[test]
+    fn test_rename_moves_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        let options = SyncOptions::default();
+        std::fs::create_dir(a.path().join("dir")).unwrap();
+        std::fs::write(a.path().join("dir/one"), b"one").unwrap();
+        std::fs::write(a.path().join("dir/two"), b"two").unwrap();
+
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+        let inode = std::fs::metadata(b.path().join("dir/one")).unwrap().ino();
+
+        // moving a directory moves each file on the other side
+        std::fs::rename(a.path().join("dir"), a.path().join("moved")).unwrap();
+        let message = nodes[0]
+            .refresh_requests(
+                a.path(),
+                &[RefreshRequest::FullRescan(a.path().to_path_buf())],
+                &options,
+                &mut stores[0],
+            )
+            .unwrap()
+            .unwrap();
+        let NodeMessage::Changes { diff, .. } = &message else {
+            panic!("expected changes, got {message:?}");
+        };
+        assert!(matches!(
+            &diff.files[&FilePath("moved/one".into())],
+            FileChange::Renamed { from, .. } if *from == FilePath("dir/one".into())
+        ));
+        exchange(&mut nodes, roots, &mut stores, [(1, message)].into());
+        assert!(!b.path().join("dir").exists());
+        assert_eq!(std::fs::read(b.path().join("moved/two")).unwrap(), b"two");
+        let moved = std::fs::metadata(b.path().join("moved/one")).unwrap();
+        assert_eq!(moved.ino(), inode);
+        assert_eq!(nodes[0].this_state, nodes[1].this_state);
+        assert!(nodes[0].is_settle());
+
+        // a rename of a file edited on the other side is a conflict there
+        std::fs::rename(a.path().join("moved/one"), a.path().join("one")).unwrap();
+        std::fs::write(b.path().join("moved/one"), b"edited").unwrap();
+        let message = nodes[0]
+            .refresh_requests(
+                a.path(),
+                &[RefreshRequest::FullRescan(a.path().to_path_buf())],
+                &options,
+                &mut stores[0],
+            )
+            .unwrap()
+            .unwrap();
+        exchange(&mut nodes, roots, &mut stores, [(1, message)].into());
+        assert_eq!(conflict_paths(&nodes[1]), vec![FilePath("one".into())]);
+        assert_eq!(
+            std::fs::read(b.path().join("moved/one")).unwrap(),
+            b"edited"
+        );
+        assert!(!b.path().join("one").exists());
+
+        // a rename onto a file created on the other side still removes the source there
+        std::fs::rename(a.path().join("moved/two"), a.path().join("two")).unwrap();
+        std::fs::write(b.path().join("two"), b"created").unwrap();
+        let message = nodes[0]
+            .refresh_requests(
+                a.path(),
+                &[RefreshRequest::FullRescan(a.path().to_path_buf())],
+                &options,
+                &mut stores[0],
+            )
+            .unwrap()
+            .unwrap();
+        exchange(&mut nodes, roots, &mut stores, [(1, message)].into());
+        assert!(conflict_paths(&nodes[1]).contains(&FilePath("two".into())));
+        assert!(!b.path().join("moved/two").exists());
+        let source = FilePath("moved/two".into());
+        assert!(!nodes[0].other_state.files.contains_key(&source));
+        assert!(!nodes[1].this_state.files.contains_key(&source));
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let resolve = |policy, local_is_older| {
+            let (dirs, mut stores, mut nodes) =
+                edited_on_both_sides("notes.txt", b"v1", [b"from a", b"from b"]);
+            let [a, b] = &dirs;
+
+            // b handles a's change before noticing its own
+            let local = b.path().join("notes.txt");
+            if local_is_older {
+                let an_hour_ago =
+                    std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+                std::fs::File::options()
+                    .write(true)
+                    .open(&local)
+                    .unwrap()
+                    .set_modified(an_hour_ago)
+                    .unwrap();
+            }
+            let options = SyncOptions::default();
+            let message = refresh_path(
+                &mut nodes[0],
+                a.path(),
+                "notes.txt",
+                &options,
+                &mut stores[0],
+            )
+            .unwrap();
+            let options = SyncOptions {
+                conflict_policy: policy,
+                ..Default::default()
+            };
+            let Some(NodeMessage::ChangesResponse { accepted_diff, .. }) = nodes[1]
+                .handle_message_disk(message, b.path(), &options, &mut stores[1])
+                .unwrap()
+            else {
+                panic!("expected a response");
+            };
+            let mut files: Vec<_> = std::fs::read_dir(b.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name != CACHE_DIR)
+                .collect();
+            files.sort();
+            let conflict = nodes[1].conflicts()[0].clone();
+            assert_eq!(conflict.path, FilePath("notes.txt".into()));
+            let accepted = !accepted_diff.is_empty();
+            assert_eq!(accepted, conflict.resolution == Resolution::KeptRemote);
+            (std::fs::read(&local).unwrap(), files, conflict.resolution)
+        };
+
+        let (content, files, resolution) = resolve(ConflictPolicy::KeepBoth, false);
+        assert_eq!(content, b"from b");
+        let Resolution::KeptBoth { copy } = resolution else {
+            panic!("expected a copy, got {resolution:?}");
+        };
+        assert_eq!(files, [&*copy.0, "notes.txt"]);
+        assert!(copy.0.starts_with("notes.fync-conflict-") && copy.0.ends_with(".txt"));
+
+        let (content, files, resolution) = resolve(ConflictPolicy::LocalWins, true);
+        assert_eq!(content, b"from b");
+        assert_eq!(files, ["notes.txt"]);
+        assert_eq!(resolution, Resolution::KeptLocal);
+
+        let (content, _, resolution) = resolve(ConflictPolicy::RemoteWins, false);
+        assert_eq!(content, b"from a");
+        assert_eq!(resolution, Resolution::KeptRemote);
+
+        let (content, _, resolution) = resolve(ConflictPolicy::NewestWins, false);
+        assert_eq!(content, b"from b");
+        assert_eq!(resolution, Resolution::KeptLocal);
+        let (content, _, resolution) = resolve(ConflictPolicy::NewestWins, true);
+        assert_eq!(content, b"from a");
+        assert_eq!(resolution, Resolution::KeptRemote);
+    }
+
+    #[test]
+    fn test_merge_text_edits() {
+        let edit = |merge, a_text: &str, b_text: &str| {
+            let (dirs, mut stores, mut nodes) = edited_on_both_sides(
+                "main.rs",
+                b"one\ntwo\nthree\n",
+                [a_text.as_bytes(), b_text.as_bytes()],
+            );
+            let [a, b] = &dirs;
+            let roots = [a.path(), b.path()];
+            let options = SyncOptions {
+                merge,
+                ..Default::default()
+            };
+            let refresh = |node: &mut Node, root: &Path, cs: &mut ContentStore| {
+                refresh_path(node, root, "main.rs", &options, cs)
+            };
+            let message = refresh(&mut nodes[0], a.path(), &mut stores[0]).unwrap();
+            nodes[1]
+                .handle_message_disk(message, b.path(), &options, &mut stores[1])
+                .unwrap();
+            // the merge is sent back like a local edit
+            if let Some(message) = refresh(&mut nodes[1], b.path(), &mut stores[1]) {
+                exchange(&mut nodes, roots, &mut stores, [(0, message)].into());
+            }
+            let merged = std::fs::read_to_string(b.path().join("main.rs")).unwrap();
+            (
+                merged,
+                std::fs::read_to_string(a.path().join("main.rs")).unwrap(),
+                nodes,
+            )
+        };
+
+        let (merged, other, nodes) =
+            edit(MergeMode::Clean, "ONE\ntwo\nthree\n", "one\ntwo\nTHREE\n");
+        assert_eq!(merged, "ONE\ntwo\nTHREE\n");
+        assert_eq!(other, merged);
+        assert!(!nodes[1].has_conflicts());
+        assert!(nodes[0].is_settle() && nodes[1].is_settle());
+
+        // overlapping edits with markers
+        let (merged, other, nodes) = edit(
+            MergeMode::Markers,
+            "first\ntwo\nthree\n",
+            "uno\ntwo\nthree\n",
+        );
+        assert_eq!(conflict_paths(&nodes[1]), vec![FilePath("main.rs".into())]);
+        assert!(merged.starts_with("<<<<<<< ours\nuno\n=======\nfirst\n>>>>>>> theirs\n"));
+        // the other node keeps its version until the conflict is resolved
+        assert_eq!(other, "first\ntwo\nthree\n");
+
+        // or the conflict policy, the other node's version goes to a copy
+        let (merged, other, nodes) =
+            edit(MergeMode::Clean, "first\ntwo\nthree\n", "uno\ntwo\nthree\n");
+        assert_eq!(merged, "uno\ntwo\nthree\n");
+        assert_eq!(other, "first\ntwo\nthree\n");
+        assert!(matches!(
+            nodes[1].conflicts()[0].resolution,
+            Resolution::KeptBoth { .. }
+        ));
+    }
+
+    #[test]
+    fn test_resolve_conflict() {
+        let resolve = |choice: ConflictChoice| {
+            // both sides edit and exchange their changes, each keeps its own
+            let (dirs, mut stores, mut nodes) =
+                edited_on_both_sides("notes.bin", b"v1", [b"from a", b"from b"]);
+            let [_, b] = &dirs;
+            let roots = dirs.each_ref().map(|dir| dir.path());
+            let options = SyncOptions::default();
+            let pending = (0..2)
+                .map(|i| {
+                    let message = refresh_path(
+                        &mut nodes[i],
+                        roots[i],
+                        "notes.bin",
+                        &options,
+                        &mut stores[i],
+                    );
+                    (1 - i, message.unwrap())
+                })
+                .collect();
+            exchange(&mut nodes, roots, &mut stores, pending);
+            assert!(nodes[0].has_conflicts() && nodes[1].has_conflicts());
+
+            let request = ControlRequest::ShowConflict {
+                path: "notes.bin".into(),
+            };
+            let (response, _) =
+                handle_control_request(&mut nodes[1], request, b.path(), &options, &mut stores[1]);
+            let ControlResponse::Versions {
+                base,
+                local,
+                remote,
+            } = response
+            else {
+                panic!("expected versions, got {response:?}");
+            };
+            assert_eq!(base, ConflictVersion::File(b"v1".to_vec()));
+            assert_eq!(local, ConflictVersion::File(b"from b".to_vec()));
+            assert_eq!(remote, ConflictVersion::File(b"from a".to_vec()));
+
+            let request = ControlRequest::ResolveConflict {
+                path: "notes.bin".into(),
+                choice,
+            };
+            let (response, message) =
+                handle_control_request(&mut nodes[1], request, b.path(), &options, &mut stores[1]);
+            assert!(matches!(response, ControlResponse::Resolved));
+            exchange(
+                &mut nodes,
+                roots,
+                &mut stores,
+                [(0, message.unwrap())].into(),
+            );
+            assert!(nodes[0].is_settle() && nodes[1].is_settle());
+            assert!(!nodes[0].has_conflicts() && !nodes[1].has_conflicts());
+            roots.map(|root| std::fs::read(root.join("notes.bin")).unwrap())
+        };
+
+        assert_eq!(resolve(ConflictChoice::Ours), [b"from b"; 2]);
+        assert_eq!(resolve(ConflictChoice::Theirs), [b"from a"; 2]);
+        let resolved = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(resolved.path(), b"by hand").unwrap();
+        let choice = ConflictChoice::File(resolved.path().to_path_buf());
+        assert_eq!(resolve(choice), [b"by hand"; 2]);
+    }
+
+    #[test]
+    fn test_resume_after_reconnect() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        std::fs::write(a.path().join("shared"), b"v1").unwrap();
+        std::fs::write(a.path().join("left"), b"l1").unwrap();
+        std::fs::write(a.path().join("right"), b"r1").unwrap();
+
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let nodes = init_nodes(roots, &mut stores);
+        assert_eq!(std::fs::read(b.path().join("shared")).unwrap(), b"v1");
+        for (node, (root, cs)) in nodes.iter().zip(roots.iter().zip(&stores)) {
+            node.save_state(root, cs).unwrap();
+        }
+
+        // both sides edit while disconnected
+        std::fs::write(a.path().join("left"), b"l2").unwrap();
+        std::fs::write(b.path().join("right"), b"r2").unwrap();
+        std::fs::write(a.path().join("shared"), b"from a").unwrap();
+        std::fs::write(b.path().join("shared"), b"from b").unwrap();
+
+        // reconnecting with override still resumes instead of clobbering b
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+        let pending = (0..2)
+            .filter_map(|i| {
+                let message = nodes[i].messages_for_other(&mut stores[i]).unwrap()?;
+                Some((1 - i, message))
+            })
+            .collect();
+        exchange(&mut nodes, roots, &mut stores, pending);
+
+        for root in roots {
+            assert_eq!(std::fs::read(root.join("left")).unwrap(), b"l2");
+            assert_eq!(std::fs::read(root.join("right")).unwrap(), b"r2");
+        }
+        // concurrent edits are conflicts, each side keeps its own
+        assert_eq!(std::fs::read(a.path().join("shared")).unwrap(), b"from a");
+        assert_eq!(std::fs::read(b.path().join("shared")).unwrap(), b"from b");
+        assert!(nodes[0].has_conflicts());
+        assert!(nodes[1].has_conflicts());
+    }
+
+    #[test]
+    fn test_no_resume_from_different_states() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        let options = SyncOptions::default();
+        std::fs::write(a.path().join("old"), b"old").unwrap();
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+
+        // the connection drops after b applied a's change, before a heard back, and
+        // each side saved what it knew then
+        std::fs::write(a.path().join("new"), b"new").unwrap();
+        let message = refresh_path(&mut nodes[0], a.path(), "new", &options, &mut stores[0]);
+        nodes[1]
+            .handle_message_disk(message.unwrap(), b.path(), &options, &mut stores[1])
+            .unwrap();
+        for i in 0..2 {
+            nodes[i].save_state(roots[i], &stores[i]).unwrap();
+        }
+        std::fs::remove_file(b.path().join("new")).unwrap();
+
+        // resuming would send a's creation and b's removal past each other
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+        let pending = (0..2)
+            .filter_map(|i| {
+                let message = nodes[i].messages_for_other(&mut stores[i]).unwrap()?;
+                Some((1 - i, message))
+            })
+            .collect();
+        exchange(&mut nodes, roots, &mut stores, pending);
+        assert_eq!(nodes[0].this_state, nodes[1].this_state);
+        assert_eq!(
+            path_exists(&a.path().join("new")),
+            path_exists(&b.path().join("new"))
+        );
+        assert!(!nodes[0].has_conflicts() && !nodes[1].has_conflicts());
+    }
+
+    #[test]
+    fn test_conflicts_survive_restart() {
+        let (dirs, mut stores, mut nodes) =
+            edited_on_both_sides("notes.txt", b"v1", [b"from a", b"from b"]);
+        let [a, b] = &dirs;
+        let roots = [a.path(), b.path()];
+        let options = SyncOptions::default();
+        let open_stores = || roots.map(|root| ContentStore::on_disk(root, &options).unwrap());
+        let save = |nodes: &[Node; 2], stores: &[ContentStore; 2]| {
+            for i in 0..2 {
+                nodes[i].save_state(roots[i], &stores[i]).unwrap();
+            }
+        };
+        let reconnect = || {
+            let mut stores = open_stores();
+            let mut nodes = init_nodes(roots, &mut stores);
+            let pending = (0..2)
+                .filter_map(|i| {
+                    let message = nodes[i].messages_for_other(&mut stores[i]).unwrap()?;
+                    Some((1 - i, message))
+                })
+                .collect();
+            exchange(&mut nodes, roots, &mut stores, pending);
+            (nodes, stores)
+        };
+        let pending = (0..2)
+            .map(|i| {
+                let message = refresh_path(
+                    &mut nodes[i],
+                    roots[i],
+                    "notes.txt",
+                    &options,
+                    &mut stores[i],
+                );
+                (1 - i, message.unwrap())
+            })
+            .collect();
+        exchange(&mut nodes, roots, &mut stores, pending);
+        // both nodes run on this host, so their conflict copies would conflict too
+        for (node, root) in nodes.iter().zip(roots) {
+            let Resolution::KeptBoth { copy } = &node.conflicts()[0].resolution else {
+                panic!("expected a copy");
+            };
+            std::fs::remove_file(copy.to_absolute(root)).unwrap();
+        }
+        let conflicts = nodes.each_ref().map(|node| node.conflicts().to_vec());
+
+        // resuming doesn't send either version over the other
+        save(&nodes, &stores);
+        let (nodes, stores) = reconnect();
+        assert_eq!(
+            nodes.each_ref().map(|node| node.conflicts().to_vec()),
+            conflicts
+        );
+        assert_eq!(
+            std::fs::read(a.path().join("notes.txt")).unwrap(),
+            b"from a"
+        );
+        assert_eq!(
+            std::fs::read(b.path().join("notes.txt")).unwrap(),
+            b"from b"
+        );
+
+        // neither does an override
+        save(&nodes, &stores);
+        std::fs::remove_dir_all(cache_dir(a.path()).join("peers")).unwrap();
+        let (mut nodes, mut stores) = reconnect();
+        assert_eq!(
+            nodes.each_ref().map(|node| node.conflicts().to_vec()),
+            conflicts
+        );
+        assert_eq!(
+            std::fs::read(b.path().join("notes.txt")).unwrap(),
+            b"from b"
+        );
+
+        // the other node's version is still there to resolve with
+        let request = ControlRequest::ResolveConflict {
+            path: "notes.txt".into(),
+            choice: ConflictChoice::Theirs,
+        };
+        let (_, message) =
+            handle_control_request(&mut nodes[1], request, b.path(), &options, &mut stores[1]);
+        exchange(
+            &mut nodes,
+            roots,
+            &mut stores,
+            [(0, message.unwrap())].into(),
+        );
+        assert!(!nodes[0].has_conflicts() && !nodes[1].has_conflicts());
+        assert_eq!(
+            std::fs::read(b.path().join("notes.txt")).unwrap(),
+            b"from a"
+        );
+    }
+
+    /// Like [`exchange`], for nodes that only keep their state in memory.
+    fn exchange_mem(
+        nodes: [&mut Node; 2],
+        stores: [&mut ContentStore; 2],
+        mut pending: VecDeque<(usize, NodeMessage)>,
+    ) {
+        while let Some((to, message)) = pending.pop_front() {
+            if let Some(response) = nodes[to].handle_message_mem(message, stores[to]).unwrap() {
+                pending.push_back((1 - to, response));
+            }
+            let chunks = nodes[to].next_chunks(stores[to]);
+            pending.extend(chunks.into_iter().map(|chunk| (1 - to, chunk)));
+        }
+    }
+
+    /// Content larger than [`CHUNKED_CONTENT_BYTES`], without long runs a delta or
+    /// compression would shrink.
+    fn large_content(seed: u8) -> Vec<u8> {
+        let len = CHUNKED_CONTENT_BYTES as usize * 5 / 4;
+        let mut content = Vec::with_capacity(len);
+        let mut hash = blake3::hash(&[seed]);
+        while content.len() < len {
+            content.extend_from_slice(hash.as_bytes());
+            hash = blake3::hash(hash.as_bytes());
+        }
+        content
+    }
+
+    #[test]
+    fn test_chunked_transfer() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let mut cs1 = ContentStore::on_disk(a.path(), &SyncOptions::default()).unwrap();
+        let mut cs2 = ContentStore::on_disk(b.path(), &SyncOptions::default()).unwrap();
+        let big = large_content(0);
+        let big_hash = cs1.add(big.clone()).unwrap();
+        let small_hash = cs1.add(b"small".to_vec()).unwrap();
+        let mut node1 = Node::new(FsState::empty(), FsState::empty());
+        let mut node2 = Node::new(FsState::empty(), FsState::empty());
+        node1.this_state.insert_file("big", big_hash);
+        node1.this_state.insert_file("small", small_hash);
+
+        // the small file doesn't wait for the big one
+        let message = node1.messages_for_other(&mut cs1).unwrap().unwrap();
+        let NodeMessage::Changes { diff, .. } = &message else {
+            unreachable!()
+        };
+        assert_eq!(
+            diff.files.keys().collect::<Vec<_>>(),
+            [&FilePath(Arc::from("small"))]
+        );
+        let mut pending: VecDeque<_> = [(1, message)].into();
+        let mut chunks = node1.next_chunks(&cs1);
+        // only a window of chunks is sent ahead
+        assert!(node1.next_chunks(&cs1).is_empty());
+        // a corrupted chunk makes the receiver reject the content, so it is sent again
+        let NodeMessage::Chunk { data, .. } = &mut chunks[1] else {
+            unreachable!()
+        };
+        data[0] ^= 1;
+        pending.extend(chunks.into_iter().map(|chunk| (1, chunk)));
+
+        exchange_mem([&mut node1, &mut node2], [&mut cs1, &mut cs2], pending);
+        assert!(node1.is_settle());
+        assert_eq!(node2.this_state, node1.this_state);
+        assert_eq!(*cs2.get(&big_hash).unwrap(), big);
+        let partial_dir = b.path().join(CACHE_DIR).join("partial");
+        assert_eq!(std::fs::read_dir(partial_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_override_streams_large_files() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let roots = [a.path(), b.path()];
+        std::fs::write(a.path().join("big"), large_content(0)).unwrap();
+        std::fs::write(a.path().join("small"), b"small").unwrap();
+        std::fs::write(b.path().join("big"), b"old").unwrap();
+
+        let mut stores = [ContentStore::default(), ContentStore::default()];
+        let mut nodes = init_nodes(roots, &mut stores);
+        // the big file is left alone by the override
+        assert_eq!(std::fs::read(b.path().join("small")).unwrap(), b"small");
+        assert_eq!(std::fs::read(b.path().join("big")).unwrap(), b"old");
+
+        let message = nodes[0].messages_for_other(&mut stores[0]).unwrap();
+        assert!(message.is_none());
+        exchange(&mut nodes, roots, &mut stores, VecDeque::new());
+        assert_eq!(
+            std::fs::read(b.path().join("big")).unwrap(),
+            large_content(0)
+        );
+        assert!(nodes[0].is_settle());
+        assert!(!nodes[1].has_conflicts());
+    }
+
+    #[test]
+    fn test_dedup_copy() {
+        let mut cs1 = ContentStore {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut cs2 = ContentStore::default();
+        let original = large_content(0);
+        let original_hash = cs1.add(original.clone()).unwrap();
+        cs2.add(original.clone()).unwrap();
+        cs1.seen_from_other(&original_hash);
+        let mut state = FsState::empty();
+        state.insert_file("original", original_hash);
+        let mut node1 = Node::new(state.clone(), state.clone());
+        let mut node2 = Node::new(state.clone(), state);
+
+        // a copy that grew, too large for a message, but the other node has most of it
+        let mut copy = original.clone();
+        copy.extend_from_slice(b"appended");
+        let copy_hash = cs1.add(copy.clone()).unwrap();
+        node1.this_state.insert_file("copy", copy_hash);
+        let message = node1.messages_for_other(&mut cs1).unwrap().unwrap();
+        assert!(node1.next_chunks(&cs1).is_empty());
+        let size = bincode::encode_to_vec(&message, bincode::config::standard())
+            .unwrap()
+            .len();
+        assert!(size < copy.len() / 10, "sent {size} bytes");
+
+        let response = node2
+            .handle_message_mem(message, &mut cs2)
+            .unwrap()
+            .unwrap();
+        assert!(node1
+            .handle_message_mem(response, &mut cs1)
+            .unwrap()
+            .is_none());
+        assert!(node1.is_settle());
+        assert_eq!(*cs2.get(&copy_hash).unwrap(), copy);
+    }
+
+    #[test]
+    fn test_dedup_missing_chunk() {
+        let mut cs1 = ContentStore {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut cs2 = ContentStore::default();
+        let original = large_content(0);
+        let original_hash = cs1.add(original.clone()).unwrap();
+        cs1.seen_from_other(&original_hash);
+        let mut node1 = Node::new(FsState::empty(), FsState::empty());
+        let mut node2 = Node::new(FsState::empty(), FsState::empty());
+
+        // the other node lost the content it was assumed to have, so it gets it whole
+        let mut copy = original;
+        copy.extend_from_slice(b"appended");
+        let copy_hash = cs1.add(copy.clone()).unwrap();
+        node1.this_state.insert_file("copy", copy_hash);
+        let message = node1.messages_for_other(&mut cs1).unwrap().unwrap();
+        let response = node2
+            .handle_message_mem(message, &mut cs2)
+            .unwrap()
+            .unwrap();
+        let pending = [(0, response)].into();
+        exchange_mem([&mut node1, &mut node2], [&mut cs1, &mut cs2], pending);
+        assert!(node1.is_settle());
+        assert_eq!(*cs2.get(&copy_hash).unwrap(), copy);
+    }
+
+    #[test]
+    fn test_hostile_content() {
+        use dedup::ChunkSource;
+
+        let mut cs = ContentStore {
+            limits: Limits {
+                max_frame_bytes: 1024 * 1024,
+                ..Limits::default()
+            },
+            ..Default::default()
+        };
+        let local = vec![7; 1024];
+        let local_hash = cs.add(local.clone()).unwrap();
+        let known = |len| ChunkPart::Known {
+            hash: blake3::hash(&local),
+            source: ChunkSource {
+                content: local_hash,
+                offset: 0,
+                len,
+            },
+        };
+        let hash = blake3::hash(b"claimed");
+        let mut receive = |parts| {
+            let content_diff = ContentDiff {
+                chunked_content: vec![ChunkedContent { hash, parts }],
+                ..Default::default()
+            };
+            cs.apply_content_diff_from_other(&content_diff).unwrap();
+            assert!(!cs.has(&hash));
+        };
+
+        // parts can't make this node allocate more than a chunk
+        receive(vec![known(u32::MAX)]);
+        let data = zstd::bulk::compress(&local, 0).unwrap();
+        receive(vec![ChunkPart::New {
+            len: u32::MAX,
+            data,
+        }]);
+        // nor a few bytes of known parts stand for a content larger than a message
+        receive(vec![known(1024); 1024]);
+
+        // a few bytes of compressed content can't expand past a frame, nor a delta past
+        // what deltas are made for
+        let bomb = vec![0; 2 * 1024 * 1024];
+        let bomb_hash = blake3::hash(&bomb);
+        let delta_bomb = vec![0; MAX_DELTA_BYTES + 1];
+        let delta_bomb_hash = blake3::hash(&delta_bomb);
+        let content_diff = ContentDiff {
+            compressed_content: vec![FullContent {
+                hash: bomb_hash,
+                data: zstd::bulk::compress(&bomb, 0).unwrap(),
+            }],
+            modified_content: vec![CompressedDiff {
+                old_hash: local_hash,
+                new_hash: delta_bomb_hash,
+                data: zstd::bulk::Compressor::with_dictionary(0, &local)
+                    .and_then(|mut c| c.compress(&delta_bomb))
+                    .unwrap(),
+            }],
+            ..Default::default()
+        };
+        cs.apply_content_diff_from_other(&content_diff).unwrap();
+        assert!(!cs.has(&bomb_hash));
+        assert!(!cs.has(&delta_bomb_hash));
+    }
+
+    #[test]
+    fn test_limits() {
+        // room for one of the contents below in a message
+        let limits = Limits {
+            max_frame_bytes: 100,
+            max_diff_files: 3,
+            ..Limits::default()
+        };
+        let mut cs1 = ContentStore {
+            limits,
+            ..Default::default()
+        };
+        let mut cs2 = ContentStore {
+            limits,
+            ..Default::default()
+        };
+        let mut node1 = Node::new(FsState::empty(), FsState::empty());
+        let mut node2 = Node::new(FsState::empty(), FsState::empty());
+        let mut sync = |node1: &mut Node, node2: &mut Node, cs1: &mut ContentStore| {
+            let mut message = node1.messages_for_other(cs1).unwrap();
+            let mut messages = 0;
+            while let Some(changes) = message {
+                let NodeMessage::Changes { diff, .. } = &changes else {
+                    unreachable!()
+                };
+                assert!(diff.files.len() <= limits.max_diff_files);
+                messages += 1;
+                let response = node2.handle_message_mem(changes, &mut cs2).unwrap();
+                message = node1.handle_message_mem(response.unwrap(), cs1).unwrap();
+            }
+            assert!(node1.is_settle());
+            assert_eq!(node2.this_state, node1.this_state);
+            messages
+        };
+
+        node1.this_state.insert_dir("dir");
+        for i in 0..3 {
+            let hash = cs1.add(vec![i; 30]).unwrap();
+            node1.this_state.insert_file(&format!("dir/file{i}"), hash);
+            node1.this_state.insert_dir(&format!("dir/sub{i}"));
+        }
+        assert_eq!(sync(&mut node1, &mut node2, &mut cs1), 3);
+        // the directories are removed after the paths inside them
+        node1.this_state = FsState::empty();
+        assert_eq!(sync(&mut node1, &mut node2, &mut cs1), 3);
+
+        // more than the receiver accepts
+        let mut sender_cs = ContentStore::default();
+        let mut sender = Node::new(FsState::empty(), FsState::empty());
+        for i in 0..4 {
+            sender.this_state.insert_dir(&format!("dir{i}"));
+        }
+        let message = sender.messages_for_other(&mut sender_cs).unwrap().unwrap();
+        let error = node2.handle_message_mem(message, &mut cs2).unwrap_err();
+        assert!(error.to_string().contains("--max-diff-files"), "{error}");
+        cs2.limits.max_tree_files = 2;
+        let mut sender = Node::new(FsState::empty(), FsState::empty());
+        for i in 0..3 {
+            sender.this_state.insert_dir(&format!("dir{i}"));
+        }
+        let message = sender.messages_for_other(&mut sender_cs).unwrap().unwrap();
+        let error = node2.handle_message_mem(message, &mut cs2).unwrap_err();
+        assert!(error.to_string().contains("--max-tree-files"), "{error}");
+        let chunk = NodeMessage::Chunk {
+            hash: blake3::hash(b""),
+            size: limits.max_file_bytes + 1,
+            offset: 0,
+            data: Vec::new(),
+        };
+        let error = node2.handle_message_mem(chunk, &mut cs2).unwrap_err();
+        assert!(error.to_string().contains("--max-file-mib"), "{error}");
+    }
 }
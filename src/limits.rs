@@ -0,0 +1,73 @@
+//! Bounds on what the other node can make this node allocate or store. A node that
+//! sends more gets a clear error instead of this process running out of memory.
+
+use anyhow::{bail, Result};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Limits on what is accepted from the other node. Messages this node sends stay
+/// within its own limits, so both nodes should use the same ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest frame read from the other node.
+    pub max_frame_bytes: u32,
+    /// Largest content of a single file.
+    pub max_file_bytes: u64,
+    /// Most changes in one message, larger diffs are sent in several messages.
+    pub max_diff_files: usize,
+    /// Most paths in the tree of the other node.
+    pub max_tree_files: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frame_bytes: 256 * MIB as u32,
+            max_file_bytes: 64 * 1024 * MIB,
+            max_diff_files: 100_000,
+            max_tree_files: 2_000_000,
+        }
+    }
+}
+
+impl Limits {
+    /// Bytes of content in a message this node sends. Half of a frame, which leaves
+    /// room for the paths. Contents beyond it are sent in later messages.
+    pub(crate) fn message_content_bytes(&self) -> u64 {
+        u64::from(self.max_frame_bytes) / 2
+    }
+
+    pub(crate) fn check_diff_files(&self, files: usize) -> Result<()> {
+        if files > self.max_diff_files {
+            bail!(
+                "Other node sent {files} changes in one message, more than the limit of {} \
+                 (--max-diff-files)",
+                self.max_diff_files
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_tree_files(&self, files: usize) -> Result<()> {
+        if files > self.max_tree_files {
+            bail!(
+                "Tree of the other node would have {files} paths, more than the limit of {} \
+                 (--max-tree-files)",
+                self.max_tree_files
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_file_bytes(&self, bytes: u64) -> Result<()> {
+        if bytes > self.max_file_bytes {
+            bail!(
+                "Other node sent a file of {} MiB, more than the limit of {} MiB \
+                 (--max-file-mib)",
+                bytes.div_ceil(MIB),
+                self.max_file_bytes / MIB
+            );
+        }
+        Ok(())
+    }
+}
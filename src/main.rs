@@ -1,8 +1,15 @@
 use anyhow::{bail, Context, Result};
 use bincode::config::standard;
-use clap::{Parser, Subcommand};
+use clap::ValueEnum;
+use clap::{ArgGroup, Parser, Subcommand};
 use crossbeam_channel::{Receiver, RecvError, RecvTimeoutError, Sender};
-use fync::{watch_root, AnyNodeMessage, ContentStore, NodeInit, NodeMessage, RefreshRequest};
+use fync::{
+    decode_message, handle_control_request, sandbox_to_root, send_control_request, serve_control,
+    watch_root, AnyNodeMessage, ConflictChoice, ConflictPolicy, ConflictVersion, ContentStore,
+    ControlCall, ControlRequest, ControlResponse, Features, FrameReader, FrameWriter, Hello,
+    Limits, MergeMode, Node, NodeInit, NodeInitMessage, NodeMessage, RefreshRequest, Resolution,
+    SymlinkPolicy, SyncOptions, WatcherKind,
+};
 use regex::Regex;
 use std::collections::BTreeSet;
 use std::io::{stderr, BufReader, BufWriter, Read, Write};
@@ -20,6 +27,42 @@ struct Args {
     command: Commands,
     #[arg(short, default_value = "\\.git")]
     ignore_regex: String,
+    /// Which symlinks are synced.
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::Allow)]
+    symlinks: SymlinkPolicy,
+    /// fsync every file that is written before moving it into place.
+    #[arg(long)]
+    fsync: bool,
+    /// Sync paths matching this glob even if they are ignored otherwise.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Never sync paths matching this glob.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Send large files whole instead of leaving out the chunks the other side has.
+    #[arg(long)]
+    no_dedup: bool,
+    /// What to do with a change from the other side to a file that was also changed here.
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::KeepBoth)]
+    on_conflict: ConflictPolicy,
+    /// Whether edits of a text file on both sides are merged before `--on-conflict` applies.
+    #[arg(long, value_enum, default_value_t = MergeMode::Clean)]
+    merge: MergeMode,
+    /// Largest message accepted from the other side, in MiB.
+    #[arg(long, value_name = "MIB", default_value_t = Limits::default().max_frame_bytes >> 20)]
+    max_frame_mib: u32,
+    /// Largest file accepted from the other side, in MiB.
+    #[arg(long, value_name = "MIB", default_value_t = Limits::default().max_file_bytes >> 20)]
+    max_file_mib: u64,
+    /// Most changes in a message, larger batches are split.
+    #[arg(long, value_name = "N", default_value_t = Limits::default().max_diff_files)]
+    max_diff_files: usize,
+    /// Most paths accepted in the tree of the other side.
+    #[arg(long, value_name = "N", default_value_t = Limits::default().max_tree_files)]
+    max_tree_files: usize,
+    /// How changes to the root are noticed.
+    #[arg(long, value_enum, default_value_t = WatcherKind::Inotify)]
+    watcher: WatcherKind,
 }
 #[derive(Subcommand, Debug)]
 enum Commands {
@@ -34,6 +77,9 @@ enum Commands {
         root: PathBuf,
         #[arg(short)]
         override_other: bool,
+        /// Deny this process access to anything outside the root, with Landlock.
+        #[arg(long)]
+        sandbox: bool,
     },
     SshSync {
         local_root: PathBuf,
@@ -41,6 +87,39 @@ enum Commands {
         remote_root: PathBuf,
         #[arg(short)]
         override_remote: bool,
+        /// Deny the remote process access to anything outside its root, with Landlock.
+        #[arg(long)]
+        sandbox_remote: bool,
+    },
+    /// Inspect and resolve the conflicts of a running session.
+    Conflicts {
+        /// Root synced by the session.
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+        #[command(subcommand)]
+        command: ConflictsCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConflictsCommand {
+    /// List the conflicted paths.
+    List,
+    /// Show the base, local and remote versions of a conflicted path.
+    Show { path: String },
+    /// Resolve a conflict, and sync the outcome to the other side.
+    #[command(group(ArgGroup::new("choice").required(true)))]
+    Resolve {
+        path: String,
+        /// Keep the local file as it is now.
+        #[arg(long, group = "choice")]
+        ours: bool,
+        /// Take the version of the other side.
+        #[arg(long, group = "choice")]
+        theirs: bool,
+        /// Take the content of this file.
+        #[arg(long, group = "choice", value_name = "PATH")]
+        file: Option<PathBuf>,
     },
 }
 
@@ -50,94 +129,198 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     let regex = Regex::new(&args.ignore_regex)?;
+    let options = SyncOptions {
+        symlink_policy: args.symlinks,
+        fsync: args.fsync,
+        include: args.include,
+        exclude: args.exclude,
+        features: if args.no_dedup {
+            Features::ALL.without(Features::DEDUP)
+        } else {
+            Features::ALL
+        },
+        conflict_policy: args.on_conflict,
+        merge: args.merge,
+        limits: Limits {
+            max_frame_bytes: args
+                .max_frame_mib
+                .checked_mul(1 << 20)
+                .context("--max-frame-mib must be below 4096")?,
+            max_file_bytes: args.max_file_mib.saturating_mul(1 << 20),
+            max_diff_files: args.max_diff_files,
+            max_tree_files: args.max_tree_files,
+        },
+        watcher: args.watcher,
+        ..Default::default()
+    };
     match args.command {
         Commands::Sync {
             source,
             destination,
-        } => sync_command(source, destination, &regex),
-        Commands::Watch { directory } => watch_command(directory, &regex),
+        } => sync_command(source, destination, &regex, &options),
+        Commands::Watch { directory } => watch_command(directory, &regex, &options),
         Commands::RunStdio {
             root,
             override_other,
-        } => run_node_stdio(&root, override_other, &regex),
+            sandbox,
+        } => run_node_stdio(&root, override_other, sandbox, &regex, &options),
         Commands::SshSync {
             local_root,
             remote_host,
             remote_root,
             override_remote,
+            sandbox_remote,
         } => ssh_sync_command_with_retry(
             local_root,
             remote_host,
             remote_root,
             override_remote,
+            sandbox_remote,
             &args.ignore_regex,
+            &options,
         ),
+        Commands::Conflicts { root, command } => conflicts_command(&root, command),
     }
 }
 
-fn watch_command(directory: PathBuf, ignore: &Regex) -> Result<()> {
+fn conflicts_command(root: &Path, command: ConflictsCommand) -> Result<()> {
+    let request = match command {
+        ConflictsCommand::List => ControlRequest::ListConflicts,
+        ConflictsCommand::Show { path } => ControlRequest::ShowConflict { path },
+        ConflictsCommand::Resolve {
+            path,
+            ours,
+            theirs,
+            file,
+        } => {
+            let choice = match file {
+                // the session runs elsewhere, so it gets an absolute path
+                Some(file) => ConflictChoice::File(file.canonicalize()?),
+                None if theirs => ConflictChoice::Theirs,
+                None => {
+                    assert!(ours);
+                    ConflictChoice::Ours
+                }
+            };
+            ControlRequest::ResolveConflict { path, choice }
+        }
+    };
+    match send_control_request(root, &request)? {
+        ControlResponse::Conflicts(conflicts) => {
+            for conflict in conflicts {
+                let path = &conflict.path;
+                match conflict.resolution {
+                    Resolution::KeptLocal => println!("{path}: kept the local version"),
+                    Resolution::KeptRemote => println!("{path}: took the other side's version"),
+                    Resolution::KeptBoth { copy } => {
+                        println!("{path}: kept the local version, the other side's is in {copy}")
+                    }
+                    Resolution::Merged => println!("{path}: merged"),
+                    Resolution::MergedWithMarkers => {
+                        println!("{path}: merged with conflict markers")
+                    }
+                }
+            }
+        }
+        ControlResponse::Versions {
+            base,
+            local,
+            remote,
+        } => {
+            for (name, version) in [("base", base), ("local", local), ("remote", remote)] {
+                println!("=== {name} ===");
+                match version {
+                    ConflictVersion::Absent => println!("(absent)"),
+                    ConflictVersion::File(content) => match String::from_utf8(content) {
+                        Ok(text) => print!("{text}"),
+                        Err(e) => println!("(binary, {} bytes)", e.as_bytes().len()),
+                    },
+                    ConflictVersion::Symlink(target) => println!("(symlink to {target})"),
+                    ConflictVersion::Directory => println!("(directory)"),
+                    ConflictVersion::Unavailable => println!("(content no longer available)"),
+                }
+            }
+        }
+        ControlResponse::Resolved => {}
+        ControlResponse::Error(e) => bail!("{e}"),
+    }
+    Ok(())
+}
+
+fn watch_command(directory: PathBuf, ignore: &Regex, options: &SyncOptions) -> Result<()> {
+    let directory = directory.canonicalize()?;
     let (watch_tx, watch_rx) = crossbeam_channel::bounded(32);
-    let _watcher = watch_root(&directory, move |paths| {
+    let _watcher = watch_root(&directory, options, move |paths| {
         let _ = watch_tx.send(paths);
     });
 
-    loop {
-        match debounce_watcher(watch_rx.recv()?, &watch_rx, ignore) {
-            Ok(paths) => {
-                println!("Changes detected:");
-                for path in paths {
-                    println!("  {:?}", path);
-                }
-            }
-            Err(RecvError) => break,
+    while let Ok(paths) = debounce_watcher(watch_rx.recv()?, &watch_rx, ignore) {
+        println!("Changes detected:");
+        for path in paths {
+            println!("  {:?}", path);
         }
     }
 
     Ok(())
 }
 
-fn sync_command(src: PathBuf, dst: PathBuf, ignore: &Regex) -> Result<()> {
+fn sync_command(src: PathBuf, dst: PathBuf, ignore: &Regex, options: &SyncOptions) -> Result<()> {
     let src_root = src.canonicalize()?;
     let dst_root = dst.canonicalize()?;
 
     let (dst_out, src_in) = crossbeam_channel::bounded::<AnyNodeMessage>(32);
     let (src_out, dst_in) = crossbeam_channel::bounded::<AnyNodeMessage>(32);
+    // each root has its own ignore files, and the source is the local side for conflicts
+    let dst_options = SyncOptions {
+        ignore_filter: Default::default(),
+        conflict_policy: options.conflict_policy.for_other_node(),
+        ..options.clone()
+    };
     scope(|s| {
-        s.spawn(|| run_node(&src_root, src_in, src_out, true, ignore));
-        s.spawn(|| run_node(&dst_root, dst_in, dst_out, false, ignore));
+        s.spawn(|| run_node(&src_root, src_in, src_out, true, ignore, options));
+        s.spawn(|| run_node(&dst_root, dst_in, dst_out, false, ignore, &dst_options));
         info!("Watching for changes. Press Ctrl+C to exit.");
     });
     Ok(())
 }
 
+/// Messages read from the other node that wait for the node to handle them.
+const INPUT_QUEUE_LEN: usize = 16;
+
 fn run_node_with_io<R: Read + Send + 'static, W: Write + Send + 'static>(
     root: &Path,
     override_other: bool,
     ignore: &Regex,
+    options: &SyncOptions,
     reader: R,
     writer: W,
 ) -> Result<()> {
-    let (input_tx, input_rx) = crossbeam_channel::unbounded();
+    // Reading stops while the node is behind, so the other node can't queue up more
+    // than a few messages in memory. Only one direction is bounded, so the two nodes
+    // can't end up waiting on each other.
+    let (input_tx, input_rx) = crossbeam_channel::bounded(INPUT_QUEUE_LEN);
     let (output_tx, output_rx) = crossbeam_channel::unbounded();
 
+    let max_frame_bytes = options.limits.max_frame_bytes;
     let read_thread = std::thread::spawn(move || -> Result<()> {
-        let mut reader = BufReader::new(reader);
-        loop {
-            let msg = bincode::decode_from_reader(&mut reader, standard())?;
+        let mut reader = FrameReader::new(BufReader::new(reader)).with_max_len(max_frame_bytes);
+        while let Some(frame) = reader.read_frame()? {
+            let msg =
+                decode_message(&frame).context("Failed to decode message from the other node")?;
             input_tx.send(msg)?;
         }
+        Ok(())
     });
 
     let write_thread = std::thread::spawn(move || {
-        let mut writer = BufWriter::new(writer);
+        let mut writer = FrameWriter::new(BufWriter::new(writer));
         while let Ok(msg) = output_rx.recv() {
-            bincode::encode_into_std_write(&msg, &mut writer, standard())?;
-            writer.flush()?;
+            writer.write_frame(&bincode::encode_to_vec(&msg, standard())?)?;
         }
         anyhow::Ok(())
     });
 
-    let result = run_node(&root, input_rx, output_tx, override_other, &ignore);
+    let result = run_node(root, input_rx, output_tx, override_other, ignore, options);
 
     read_thread
         .join()
@@ -151,12 +334,24 @@ fn run_node_with_io<R: Read + Send + 'static, W: Write + Send + 'static>(
     result
 }
 
-fn run_node_stdio(root: &Path, override_other: bool, ignore: &Regex) -> Result<()> {
+fn run_node_stdio(
+    root: &Path,
+    override_other: bool,
+    sandbox: bool,
+    ignore: &Regex,
+    options: &SyncOptions,
+) -> Result<()> {
     let root = root.canonicalize()?;
+    if sandbox {
+        // read while they can still be reached
+        options.ignore_filter.load_global_excludes();
+        sandbox_to_root(&root)?;
+    }
     run_node_with_io(
         &root,
         override_other,
         ignore,
+        options,
         std::io::stdin(),
         std::io::stdout(),
     )
@@ -169,21 +364,31 @@ fn run_node(
     output: Sender<AnyNodeMessage>,
     override_other: bool,
     ignore: &Regex,
+    options: &SyncOptions,
 ) -> std::result::Result<(), anyhow::Error> {
     // first start watching
     let (watch_tx, watch_rx) = crossbeam_channel::bounded(32);
-    let _watcher = watch_root(root, move |paths| {
+    let _watcher = watch_root(root, options, move |paths| {
         // FIXME: stop watching if other side dies.
         let _ = watch_tx.send(paths);
     });
-    let content_store = &mut ContentStore::default();
-    let mut node_init = NodeInit::from_disk(root, content_store, override_other)?;
-    output.send(AnyNodeMessage::Init(node_init.announce()))?;
+    let options = &exchange_hello(&input, &output, options)?;
+    let content_store = &mut ContentStore::on_disk(root, options)?;
+    let mut node_init = NodeInit::from_disk(root, options, content_store, override_other)?;
+    // the sender is kept here, so the loop below still works without the socket
+    let (control_tx, control_rx) = crossbeam_channel::unbounded();
+    if let Err(e) = serve_control(root, control_tx.clone()) {
+        error!("`fync conflicts` won't reach this session: {e:#}");
+    }
+    if let Some(announcement) = node_init.announce() {
+        output.send(AnyNodeMessage::Init(announcement))?;
+    }
     let mut node = loop {
         let AnyNodeMessage::Init(init_message) = input.recv()? else {
             bail!("only expected init message");
         };
-        let (node, response) = node_init.handle_init_message(root, init_message, content_store)?;
+        let (node, response) =
+            node_init.handle_init_message(root, init_message, options, content_store)?;
         if let Some(response) = response {
             output.send(AnyNodeMessage::Init(response))?;
         }
@@ -193,29 +398,45 @@ fn run_node(
     };
 
     info!("Initial sync completed successfully");
+    // after resuming, send what changed here while disconnected
+    if let Some(message) = node.messages_for_other(content_store)? {
+        output.send(AnyNodeMessage::Regular(message))?;
+    }
+    send_chunks(&mut node, &output, content_store)?;
+    save_state(&node, root, content_store);
+    let mut last_save = Instant::now();
+    let mut last_gc = Instant::now();
 
     loop {
         #[derive(Debug)]
         enum Event {
             Message(NodeMessage),
             Refresh(Vec<RefreshRequest>),
+            Control(ControlCall),
         }
-        let event = crossbeam_channel::select! {
-            recv(input) -> msg => {
-                if let Ok(msg) = msg {
-                    let AnyNodeMessage::Regular(msg) = msg else {
-                        bail!("only expected regular message, found init message");
-                    };
-                    Event::Message(msg)
-                } else {
-                    break;
+        // files that changed while their changes were sent are hashed again first
+        let stale = node.stale_requests(root);
+        let event = if !stale.is_empty() {
+            Event::Refresh(stale)
+        } else {
+            crossbeam_channel::select! {
+                recv(input) -> msg => {
+                    if let Ok(msg) = msg {
+                        let AnyNodeMessage::Regular(msg) = msg else {
+                            bail!("only expected regular message, found init message");
+                        };
+                        Event::Message(msg)
+                    } else {
+                        break;
+                    }
                 }
-            }
-            recv(watch_rx) -> path_list => {
-                match debounce_watcher(path_list?, &watch_rx, ignore) {
-                    Ok(paths) => Event::Refresh(paths),
-                    Err(RecvError) => break,
+                recv(watch_rx) -> path_list => {
+                    match debounce_watcher(path_list?, &watch_rx, ignore) {
+                        Ok(paths) => Event::Refresh(paths),
+                        Err(RecvError) => break,
+                    }
                 }
+                recv(control_rx) -> call => Event::Control(call?),
             }
         };
         debug!(?event, "Processing event");
@@ -225,38 +446,110 @@ fn run_node(
                     NodeMessage::Changes { diff, .. } => {
                         info!("Received Changes: {} files", diff.files.len());
                     }
-                    NodeMessage::ChangesResponse { accepted_diff } => {
+                    NodeMessage::ChangesResponse { accepted_diff, .. } => {
                         info!(
                             "Received Response: {} files accepted",
                             accepted_diff.files.len()
                         );
                     }
+                    NodeMessage::Chunk { .. }
+                    | NodeMessage::ChunkAck { .. }
+                    | NodeMessage::ChunkRejected { .. } => {}
                 }
-                node.handle_message_disk(msg, root, content_store)?
+                node.handle_message_disk(msg, root, options, content_store)?
+            }
+            Event::Refresh(path_list) => {
+                node.refresh_requests(root, &path_list, options, content_store)?
+            }
+            Event::Control((request, response_tx)) => {
+                let (response, message) =
+                    handle_control_request(&mut node, request, root, options, content_store);
+                let _ = response_tx.send(response);
+                message
             }
-            Event::Refresh(path_list) => node.refresh_requests(root, &path_list, content_store)?,
         };
         if let Some(response) = response {
             match &response {
-                NodeMessage::Changes {
-                    content_diff: _,
-                    diff,
-                } => {
+                NodeMessage::Changes { diff, .. } => {
                     info!("Sending Changes: {} files", diff.files.len());
                 }
-                NodeMessage::ChangesResponse { accepted_diff } => {
+                NodeMessage::ChangesResponse { accepted_diff, .. } => {
                     info!(
                         "Sending Response: {} files accepted",
                         accepted_diff.files.len()
                     );
                 }
+                NodeMessage::Chunk { .. }
+                | NodeMessage::ChunkAck { .. }
+                | NodeMessage::ChunkRejected { .. } => {}
             }
             output.send(AnyNodeMessage::Regular(response))?;
         }
+        send_chunks(&mut node, &output, content_store)?;
+        if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+            save_state(&node, root, content_store);
+            last_save = Instant::now();
+        }
+        if last_gc.elapsed() >= GC_INTERVAL {
+            collect_garbage(&node, content_store);
+            last_gc = Instant::now();
+        }
     }
+    save_state(&node, root, content_store);
     anyhow::Ok(())
 }
 
+/// Streams the chunks of large files the flow control allows, between other messages.
+fn send_chunks(
+    node: &mut Node,
+    output: &Sender<AnyNodeMessage>,
+    content_store: &ContentStore,
+) -> Result<()> {
+    for chunk in node.next_chunks(content_store) {
+        output.send(AnyNodeMessage::Regular(chunk))?;
+    }
+    Ok(())
+}
+
+/// Agrees on the features to use with the other node, or fails if it is incompatible.
+fn exchange_hello(
+    input: &Receiver<AnyNodeMessage>,
+    output: &Sender<AnyNodeMessage>,
+    options: &SyncOptions,
+) -> Result<SyncOptions> {
+    let hello = Hello::new(options.features);
+    output.send(AnyNodeMessage::Init(NodeInitMessage::Hello(hello.clone())))?;
+    let AnyNodeMessage::Init(NodeInitMessage::Hello(other)) = input.recv()? else {
+        bail!("Expected hello from the other node, is it running an older fync?");
+    };
+    Ok(SyncOptions {
+        features: hello.negotiate(&other)?,
+        ..options.clone()
+    })
+}
+
+/// How often the state cache is written while syncing.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often contents no longer referenced by either node are dropped.
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+fn collect_garbage(node: &Node, content_store: &mut ContentStore) {
+    match node.collect_garbage(content_store) {
+        Ok(stats) => info!(
+            "Collected garbage: {} blobs removed, {} blobs and {} files remaining",
+            stats.removed_blobs, stats.remaining_blobs, stats.files
+        ),
+        Err(e) => error!("Failed to collect garbage: {:?}", e),
+    }
+}
+
+fn save_state(node: &Node, root: &Path, content_store: &ContentStore) {
+    // the cache is only an optimization, syncing goes on without it
+    if let Err(e) = node.save_state(root, content_store) {
+        error!("Failed to save state cache: {:?}", e);
+    }
+}
+
 fn debounce_watcher(
     path_list: Vec<RefreshRequest>,
     rx: &Receiver<Vec<RefreshRequest>>,
@@ -285,9 +578,9 @@ fn debounce_watcher(
         .filter(|x| {
             let found = match x {
                 RefreshRequest::FullRescan(path) => {
-                    path.to_str().map_or(false, |x| !ignore.is_match(x))
+                    path.to_str().is_some_and(|x| !ignore.is_match(x))
                 }
-                RefreshRequest::Path(path) => path.to_str().map_or(false, |x| !ignore.is_match(x)),
+                RefreshRequest::Path(path) => path.to_str().is_some_and(|x| !ignore.is_match(x)),
             };
             found
         })
@@ -299,7 +592,9 @@ fn ssh_sync_command_with_retry(
     remote_host: String,
     remote_root: PathBuf,
     override_remote: bool,
+    sandbox_remote: bool,
     ignore: &str,
+    options: &SyncOptions,
 ) -> Result<()> {
     const MAX_RETRIES: u32 = 10;
     const RETRY_DELAY: Duration = Duration::from_secs(5);
@@ -319,7 +614,9 @@ fn ssh_sync_command_with_retry(
             &remote_host,
             &remote_root,
             override_remote,
+            sandbox_remote,
             ignore,
+            options,
         ) {
             Ok(_) => return Ok(()),
             Err(e) => {
@@ -346,25 +643,78 @@ fn ssh_sync_command_with_retry(
     }
 }
 
+/// Passes `value` as `flag`, unless it is the default.
+fn value_arg<T: ValueEnum + Default + PartialEq>(cmd: &mut Command, flag: &str, value: T) {
+    if value != T::default() {
+        cmd.arg(flag).arg(
+            value
+                .to_possible_value()
+                .expect("no skipped variants")
+                .get_name(),
+        );
+    }
+}
+
 fn ssh_sync_command(
     local_root: &Path,
     remote_host: &str,
     remote_root: &Path,
     override_remote: bool,
+    sandbox_remote: bool,
     ignore: &str,
+    options: &SyncOptions,
 ) -> Result<()> {
     let local_root = local_root.canonicalize()?;
     let regex = Regex::new(ignore).unwrap();
     let mut cmd = Command::new("ssh");
-    cmd.arg(&remote_host)
+    cmd.arg(remote_host)
         .arg("fync")
         .arg("-i")
-        .arg(&*shlex::try_quote(ignore).unwrap())
-        .arg("run-stdio")
-        .arg(&remote_root);
+        .arg(&*shlex::try_quote(ignore).unwrap());
+    // settings at their default aren't passed, so a remote too old to know them gets as
+    // far as the hello, which reports the version mismatch
+    value_arg(&mut cmd, "--symlinks", options.symlink_policy);
+    value_arg(
+        &mut cmd,
+        "--on-conflict",
+        options.conflict_policy.for_other_node(),
+    );
+    value_arg(&mut cmd, "--merge", options.merge);
+    value_arg(&mut cmd, "--watcher", options.watcher);
+    if options.fsync {
+        cmd.arg("--fsync");
+    }
+    for glob in &options.include {
+        cmd.arg("--include").arg(&*shlex::try_quote(glob)?);
+    }
+    for glob in &options.exclude {
+        cmd.arg("--exclude").arg(&*shlex::try_quote(glob)?);
+    }
+    let limits = &options.limits;
+    let default_limits = Limits::default();
+    if limits.max_frame_bytes != default_limits.max_frame_bytes {
+        cmd.arg("--max-frame-mib")
+            .arg((limits.max_frame_bytes >> 20).to_string());
+    }
+    if limits.max_file_bytes != default_limits.max_file_bytes {
+        cmd.arg("--max-file-mib")
+            .arg((limits.max_file_bytes >> 20).to_string());
+    }
+    if limits.max_diff_files != default_limits.max_diff_files {
+        cmd.arg("--max-diff-files")
+            .arg(limits.max_diff_files.to_string());
+    }
+    if limits.max_tree_files != default_limits.max_tree_files {
+        cmd.arg("--max-tree-files")
+            .arg(limits.max_tree_files.to_string());
+    }
+    cmd.arg("run-stdio").arg(remote_root);
     if override_remote {
         cmd.arg("-o");
     }
+    if sandbox_remote {
+        cmd.arg("--sandbox");
+    }
     // Spawn the SSH process
     let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
 
@@ -375,6 +725,7 @@ fn ssh_sync_command(
         &local_root,
         !override_remote,
         &regex,
+        options,
         child_stdout,
         child_stdin,
     );
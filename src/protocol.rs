@@ -0,0 +1,128 @@
+//! The first message of a connection, so nodes running different versions of fync
+//! either agree on what they both support or refuse to talk.
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use tracing::{info, warn};
+
+/// Bumped on every change that older nodes can't decode or handle.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Optional parts of the protocol, a node only uses the ones both sides support.
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Features(u64);
+
+impl Features {
+    /// Permission bits of files are synced.
+    pub const MODES: Features = Features(1 << 0);
+    /// Symlinks are synced.
+    pub const SYMLINKS: Features = Features(1 << 1);
+    /// Large contents are sent as content-defined chunks, leaving out the chunks the
+    /// receiver already has.
+    pub const DEDUP: Features = Features(1 << 2);
+    /// Everything this version supports.
+    pub const ALL: Features = Features(Self::MODES.0 | Self::SYMLINKS.0 | Self::DEDUP.0);
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    pub fn without(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+}
+
+impl std::fmt::Debug for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (Features::MODES, "modes"),
+            (Features::SYMLINKS, "symlinks"),
+            (Features::DEDUP, "dedup"),
+        ];
+        let mut list = f.debug_set();
+        for (feature, name) in names {
+            if self.contains(feature) {
+                list.entry(&format_args!("{name}"));
+            }
+        }
+        list.finish()
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features::ALL
+    }
+}
+
+/// Layout must stay the same in every version, so any node can decode it.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub binary_version: String,
+    pub features: Features,
+}
+
+impl Hello {
+    pub fn new(features: Features) -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            binary_version: env!("CARGO_PKG_VERSION").to_string(),
+            features,
+        }
+    }
+
+    /// The features to use with the node that sent `other`.
+    pub fn negotiate(&self, other: &Hello) -> Result<Features> {
+        if other.protocol_version != self.protocol_version {
+            bail!(
+                "Other node runs fync {} with protocol version {}, but this is fync {} with \
+                 protocol version {}. Install the same version of fync on both machines.",
+                other.binary_version,
+                other.protocol_version,
+                self.binary_version,
+                self.protocol_version
+            );
+        }
+        let features = self.features.intersection(other.features);
+        info!(
+            "Connected to fync {}, using features {:?}",
+            other.binary_version, features
+        );
+        if features != self.features {
+            warn!(
+                "Other node doesn't support {:?}, they are turned off",
+                self.features.without(features)
+            );
+        }
+        Ok(features)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let this = Hello::new(Features::ALL);
+        let older = Hello::new(Features::MODES);
+        assert_eq!(this.negotiate(&older).unwrap(), Features::MODES);
+        assert_eq!(older.negotiate(&this).unwrap(), Features::MODES);
+
+        // bits this version doesn't know are dropped
+        let newer = Hello::new(Features(u64::MAX));
+        assert_eq!(this.negotiate(&newer).unwrap(), Features::ALL);
+
+        let incompatible = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::new(Features::ALL)
+        };
+        let error = this.negotiate(&incompatible).unwrap_err();
+        assert!(error.to_string().contains("protocol version"));
+    }
+}
@@ -0,0 +1,202 @@
+//! Changes to entries inside the root, made relative to the directory they are in. That
+//! directory is opened one component at a time without following symlinks, so a path
+//! from the other node can't reach outside the root, not even through a symlink swapped
+//! in while the change is applied.
+
+use rustix::{
+    fs::{AtFlags, FileType, Mode, OFlags, CWD},
+    io::Errno,
+};
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Write},
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{FilePath, PERMISSION_BITS, TEMP_FILE_PREFIX};
+
+/// An entry inside the root: the directory it is in, and its name there.
+pub(crate) struct Entry<'a> {
+    dir: OwnedFd,
+    name: &'a str,
+}
+
+/// Opens the directory `file_path` is in. Missing directories on the way are created if
+/// `create`, and are `NotFound` otherwise. Fails if one of them is a symlink or a file.
+pub(crate) fn open_entry<'a>(
+    root: &Path,
+    file_path: &'a FilePath,
+    create: bool,
+) -> io::Result<Entry<'a>> {
+    let (parents, name) = match file_path.0.rsplit_once('/') {
+        Some((parents, name)) => (Some(parents), name),
+        None => (None, file_path.0.as_ref()),
+    };
+    // the root itself may well be a symlink
+    let mut dir = rustix::fs::openat(
+        CWD,
+        root,
+        OFlags::DIRECTORY | OFlags::RDONLY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    for component in parents.into_iter().flat_map(|parents| parents.split('/')) {
+        dir = match open_dir(&dir, component) {
+            Err(Errno::NOENT) if create => {
+                match rustix::fs::mkdirat(&dir, component, Mode::from_raw_mode(0o777)) {
+                    Ok(()) | Err(Errno::EXIST) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                open_dir(&dir, component)?
+            }
+            Err(Errno::LOOP | Errno::NOTDIR) => {
+                return Err(io::Error::other(format!(
+                    "{file_path:?} passes through a symlink or a file"
+                )))
+            }
+            result => result?,
+        };
+    }
+    Ok(Entry { dir, name })
+}
+
+/// Whether the directories `file_path` is in are real directories inside the root, or
+/// missing, so a change to it stays inside the root.
+pub(crate) fn is_reachable(root: &Path, file_path: &FilePath) -> bool {
+    match open_entry(root, file_path, false) {
+        Ok(_) => true,
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    }
+}
+
+fn open_dir(dir: impl AsFd, name: &str) -> rustix::io::Result<OwnedFd> {
+    rustix::fs::openat(
+        dir,
+        name,
+        OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::RDONLY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+}
+
+impl Entry<'_> {
+    /// Permission bits of the entry, if it is a regular file.
+    pub fn file_mode(&self) -> Option<u32> {
+        let stat = rustix::fs::statat(&self.dir, self.name, AtFlags::SYMLINK_NOFOLLOW).ok()?;
+        (FileType::from_raw_mode(stat.st_mode) == FileType::RegularFile)
+            .then_some(stat.st_mode & PERMISSION_BITS)
+    }
+
+    /// Sets the permission bits of the entry, which must be a regular file. Works on
+    /// files this process can't read, e.g. with mode 0o200.
+    pub fn set_mode(&self, mode: u32) -> io::Result<()> {
+        let file = rustix::fs::openat(
+            &self.dir,
+            self.name,
+            OFlags::PATH | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        if FileType::from_raw_mode(rustix::fs::fstat(&file)?.st_mode) != FileType::RegularFile {
+            return Err(io::Error::other(format!("{:?} is not a file", self.name)));
+        }
+        // fchmod doesn't take an O_PATH descriptor, chmod through its /proc link does
+        let link = format!("/proc/self/fd/{}", file.as_raw_fd());
+        rustix::fs::chmod(link.as_str(), Mode::from_raw_mode(mode))?;
+        Ok(())
+    }
+
+    /// Writes a temporary file next to the entry, and renames it over the entry.
+    pub fn write_file(&self, content: &[u8], mode: u32, fsync: bool) -> io::Result<()> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let (temp_name, file) = loop {
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let temp_name = format!("{TEMP_FILE_PREFIX}{}-{n}", std::process::id());
+            match rustix::fs::openat(
+                &self.dir,
+                &temp_name,
+                OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+                Mode::from_raw_mode(0o600),
+            ) {
+                Err(Errno::EXIST) => continue,
+                result => break (temp_name, File::from(result?)),
+            }
+        };
+        let result = (|| {
+            (&file).write_all(content)?;
+            rustix::fs::fchmod(&file, Mode::from_raw_mode(mode))?;
+            if fsync {
+                file.sync_all()?;
+            }
+            rustix::fs::renameat(&self.dir, &temp_name, &self.dir, self.name)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = rustix::fs::unlinkat(&self.dir, &temp_name, AtFlags::empty());
+        }
+        result
+    }
+
+    pub fn symlink(&self, target: &str) -> io::Result<()> {
+        Ok(rustix::fs::symlinkat(target, &self.dir, self.name)?)
+    }
+
+    pub fn create_dir(&self) -> io::Result<()> {
+        Ok(rustix::fs::mkdirat(
+            &self.dir,
+            self.name,
+            Mode::from_raw_mode(0o777),
+        )?)
+    }
+
+    /// Removes the entry, which is an empty directory if `is_dir`. A symlink is removed
+    /// itself, never what it points to.
+    pub fn remove(&self, is_dir: bool) -> io::Result<()> {
+        let flags = if is_dir {
+            AtFlags::REMOVEDIR
+        } else {
+            AtFlags::empty()
+        };
+        Ok(rustix::fs::unlinkat(&self.dir, self.name, flags)?)
+    }
+
+    pub fn rename_to(&self, to: &Entry) -> io::Result<()> {
+        Ok(rustix::fs::renameat(
+            &self.dir, self.name, &to.dir, to.name,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_entries_stay_inside_root() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        let file_path = |path: &str| FilePath(Arc::from(path));
+
+        let new_file = file_path("new/dir/file");
+        let entry = open_entry(root.path(), &new_file, true).unwrap();
+        entry.write_file(b"hello", 0o200, false).unwrap();
+        entry.set_mode(0o640).unwrap();
+        assert_eq!(entry.file_mode(), Some(0o640));
+        assert_eq!(
+            std::fs::read(root.path().join("new/dir/file")).unwrap(),
+            b"hello"
+        );
+
+        assert!(!is_reachable(root.path(), &file_path("link/file")));
+        assert!(open_entry(root.path(), &file_path("link/file"), true).is_err());
+        assert!(is_reachable(root.path(), &file_path("missing/file")));
+        // the link itself is an entry like any other
+        open_entry(root.path(), &file_path("link"), false)
+            .unwrap()
+            .remove(false)
+            .unwrap();
+        assert!(outside.path().exists());
+        assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,41 @@
+//! Confines a node to its root with Landlock, for nodes fed by a peer that isn't trusted
+//! as much as the local user.
+
+use anyhow::{Context, Result};
+use landlock::{
+    Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Denies the current thread, and the threads it spawns later, filesystem access outside
+/// `root`, which holds the cache directory too. Kernels without Landlock, or with an older
+/// version of it, restrict as much as they support. Returns whether anything is
+/// restricted.
+pub fn sandbox_to_root(root: &Path) -> Result<bool> {
+    let access = AccessFs::from_all(ABI::V5);
+    let status = Ruleset::default()
+        .handle_access(access)?
+        .create()?
+        .add_rule(PathBeneath::new(
+            PathFd::new(root).with_context(|| format!("Failed to open {}", root.display()))?,
+            access,
+        ))?
+        .restrict_self()
+        .context("Failed to apply the Landlock ruleset")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!(?root, "Sandboxed to the root"),
+        RulesetStatus::PartiallyEnforced => {
+            warn!(
+                ?root,
+                "Sandboxed to the root, as far as this kernel's Landlock allows"
+            )
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("Landlock isn't supported by this kernel, running without a sandbox");
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
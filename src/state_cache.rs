@@ -0,0 +1,200 @@
+//! Persists a node's state between runs, so a restart doesn't rehash every file.
+
+use anyhow::{bail, Result};
+use bincode::{config::standard, Decode, Encode};
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use crate::{
+    cache_dir, write_file_atomically, Conflict, ContentStore, FileMetadata, FilePath, FileStat,
+    FsState,
+};
+
+const STATE_FILE: &str = "state";
+const NODE_ID_FILE: &str = "id";
+/// Holds the last state agreed with each peer, by the peer's node id.
+const PEERS_DIR: &str = "peers";
+/// Holds the conflicts the user hasn't resolved yet.
+const CONFLICTS_FILE: &str = "conflicts";
+/// Bumped whenever the format changes, caches with another version are discarded.
+const STATE_VERSION: u32 = 2;
+
+#[derive(Debug, Encode, Decode)]
+pub(crate) struct StateCache {
+    version: u32,
+    pub this_state: FsState,
+    /// `stat` of the files in `this_state` at the time they were hashed.
+    stats: BTreeMap<FilePath, FileStat>,
+}
+
+impl StateCache {
+    pub fn load(root: &Path) -> Option<Self> {
+        let cache: StateCache = read_cache_file(&cache_dir(root).join(STATE_FILE))?;
+        if cache.version != STATE_VERSION {
+            warn!("Discarding state cache from another version");
+            return None;
+        }
+        Some(cache)
+    }
+
+    /// Lets `content_store` skip rehashing files whose `stat` didn't change.
+    pub fn restore(&self, root: &Path, content_store: &mut ContentStore) {
+        for (file_path, stat) in &self.stats {
+            if let Some(FileMetadata::File { content_hash, .. }) =
+                self.this_state.files.get(file_path)
+            {
+                content_store.record_file(file_path.to_absolute(root), *stat, *content_hash);
+            }
+        }
+        info!("Restored state cache with {} files", self.stats.len());
+    }
+
+    pub fn save(root: &Path, this_state: &FsState, content_store: &ContentStore) -> Result<()> {
+        let stats = this_state
+            .files
+            .iter()
+            .filter_map(|(file_path, meta)| {
+                let (stat, hash) = content_store.files.get(&file_path.to_absolute(root))?;
+                (meta.content_hash() == Some(*hash)).then(|| (file_path.clone(), *stat))
+            })
+            .collect();
+        let cache = StateCache {
+            version: STATE_VERSION,
+            this_state: this_state.clone(),
+            stats,
+        };
+        write_cache_file(&cache_dir(root).join(STATE_FILE), &cache)
+    }
+}
+
+/// Node ids name files in the cache directory, so they are restricted to hex.
+pub(crate) fn validate_node_id(node_id: &str) -> Result<()> {
+    if node_id.len() != 32 || !node_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid node id: {node_id:?}");
+    }
+    Ok(())
+}
+
+/// A stable id for this root, so peers can tell which common state belongs to whom.
+pub(crate) fn load_or_create_node_id(root: &Path) -> Result<String> {
+    let path = cache_dir(root).join(NODE_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(node_id) if validate_node_id(node_id.trim()).is_ok() => {
+            return Ok(node_id.trim().to_string())
+        }
+        Ok(_) => warn!(?path, "Replacing invalid node id"),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let seed = format!("{}:{}:{}", root.display(), std::process::id(), nanos);
+    let node_id = blake3::hash(seed.as_bytes()).to_hex()[..32].to_string();
+    std::fs::create_dir_all(cache_dir(root))?;
+    write_file_atomically(&path, node_id.as_bytes(), 0o600, false)?;
+    Ok(node_id)
+}
+
+/// The last agreed state with every known peer.
+pub(crate) fn load_peer_states(root: &Path) -> BTreeMap<String, FsState> {
+    let Ok(entries) = std::fs::read_dir(cache_dir(root).join(PEERS_DIR)) else {
+        return BTreeMap::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let node_id = entry.file_name().into_string().ok()?;
+            validate_node_id(&node_id).ok()?;
+            let state = read_cache_file(&entry.path())?;
+            Some((node_id, state))
+        })
+        .collect()
+}
+
+pub(crate) fn save_peer_state(root: &Path, node_id: &str, state: &FsState) -> Result<()> {
+    validate_node_id(node_id)?;
+    write_cache_file(&cache_dir(root).join(PEERS_DIR).join(node_id), state)
+}
+
+/// The unresolved conflicts of the last run.
+pub(crate) fn load_conflicts(root: &Path) -> Vec<Conflict> {
+    read_cache_file(&cache_dir(root).join(CONFLICTS_FILE)).unwrap_or_default()
+}
+
+pub(crate) fn save_conflicts(root: &Path, conflicts: &[Conflict]) -> Result<()> {
+    write_cache_file(&cache_dir(root).join(CONFLICTS_FILE), conflicts)
+}
+
+fn read_cache_file<T: Decode>(path: &Path) -> Option<T> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(?path, "Failed to read cache file: {e}");
+            return None;
+        }
+    };
+    match bincode::decode_from_slice(&bytes, standard()) {
+        Ok((value, _)) => Some(value),
+        Err(e) => {
+            warn!(?path, "Discarding unreadable cache file: {e}");
+            None
+        }
+    }
+}
+
+fn write_cache_file<T: Encode + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let bytes = bincode::encode_to_vec(value, standard())?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_file_atomically(path, &bytes, 0o600, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyncOptions;
+
+    #[test]
+    fn test_restore_skips_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("a.txt"), b"hello").unwrap();
+        std::fs::write(root.join("b.txt"), b"world").unwrap();
+
+        let options = SyncOptions::default();
+        let mut cs = ContentStore::default();
+        let state = FsState::from_disk(root, &options, &mut cs).unwrap();
+        StateCache::save(root, &state, &cs).unwrap();
+
+        std::fs::write(root.join("b.txt"), b"changed!").unwrap();
+
+        let mut cs = ContentStore::default();
+        let cache = StateCache::load(root).unwrap();
+        cache.restore(root, &mut cs);
+        let rescanned = FsState::from_disk(root, &options, &mut cs).unwrap();
+
+        // only the changed file was read again
+        assert_eq!(cs.blobs.len(), 1);
+        assert!(cs.blobs.contains(&blake3::hash(b"changed!")));
+        assert_eq!(
+            rescanned.files.get(&FilePath("a.txt".into())),
+            state.files.get(&FilePath("a.txt".into()))
+        );
+        assert_eq!(&*cs.get(&blake3::hash(b"hello")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_node_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let node_id = load_or_create_node_id(dir.path()).unwrap();
+        assert!(validate_node_id(&node_id).is_ok());
+        assert_eq!(load_or_create_node_id(dir.path()).unwrap(), node_id);
+        assert!(validate_node_id("../../etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Contents too large for a single message are streamed in chunks. Only a window of
+//! chunks is sent ahead of the acknowledgements, so small changes keep flowing in
+//! between, and the receiver assembles the content on disk.
+
+use anyhow::{Context, Result};
+use bincode::{Decode, Encode};
+use std::{fs::File, io::Write, path::PathBuf};
+
+use crate::{ContentHash, ContentStore, NodeMessage};
+
+/// Larger contents are streamed in chunks instead of being sent in a `ContentDiff`.
+pub(crate) const CHUNKED_CONTENT_BYTES: u64 = 8 * 1024 * 1024;
+const CHUNK_BYTES: u64 = 1024 * 1024;
+/// Bytes sent ahead of the last acknowledged chunk.
+const WINDOW_BYTES: u64 = 8 * CHUNK_BYTES;
+
+/// A content being streamed to the other node.
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct Upload {
+    #[bincode(with_serde)]
+    pub hash: ContentHash,
+    size: u64,
+    sent: u64,
+    acked: u64,
+}
+
+impl Upload {
+    pub fn new(hash: ContentHash, size: u64) -> Self {
+        Upload {
+            hash,
+            size,
+            sent: 0,
+            acked: 0,
+        }
+    }
+
+    /// The next chunk to send, unless everything is sent or the window is full.
+    pub fn next_chunk(&mut self, content_store: &ContentStore) -> Result<Option<NodeMessage>> {
+        if self.sent == self.size || self.sent - self.acked >= WINDOW_BYTES {
+            return Ok(None);
+        }
+        let len = CHUNK_BYTES.min(self.size - self.sent);
+        let data = content_store.read_content_at(&self.hash, self.sent, len)?;
+        let chunk = NodeMessage::Chunk {
+            hash: self.hash,
+            size: self.size,
+            offset: self.sent,
+            data,
+        };
+        self.sent += len;
+        Ok(Some(chunk))
+    }
+
+    /// Returns whether the other node has stored the whole content. Acknowledgements of
+    /// more than was sent are ignored, and late ones don't move back what was acked.
+    pub fn acked(&mut self, received: u64) -> bool {
+        if received <= self.sent {
+            self.acked = self.acked.max(received);
+        }
+        self.acked == self.size
+    }
+}
+
+/// A content being received from the other node.
+#[derive(Debug)]
+pub(crate) struct Download {
+    size: u64,
+    received: u64,
+    hasher: blake3::Hasher,
+    data: PartialContent,
+}
+
+#[derive(Debug)]
+pub(crate) enum PartialContent {
+    Memory(Vec<u8>),
+    File(PathBuf, File),
+}
+
+impl Download {
+    /// Chunks are written to `path` if there is one, kept in memory otherwise.
+    pub fn new(size: u64, path: Option<PathBuf>) -> Result<Self> {
+        let data = match path {
+            Some(path) => {
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                let file = File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                PartialContent::File(path, file)
+            }
+            None => PartialContent::Memory(Vec::new()),
+        };
+        Ok(Download {
+            size,
+            received: 0,
+            hasher: blake3::Hasher::new(),
+            data,
+        })
+    }
+
+    /// Returns false if the chunk doesn't continue where the last one ended.
+    pub fn write(&mut self, offset: u64, chunk: &[u8]) -> Result<bool> {
+        if offset != self.received || self.size - self.received < chunk.len() as u64 {
+            return Ok(false);
+        }
+        match &mut self.data {
+            PartialContent::Memory(data) => data.extend_from_slice(chunk),
+            PartialContent::File(_, file) => file.write_all(chunk)?,
+        }
+        self.hasher.update(chunk);
+        self.received += chunk.len() as u64;
+        Ok(true)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    /// The assembled content, if it matches `hash`.
+    pub fn finish(mut self, hash: &ContentHash) -> Option<PartialContent> {
+        if self.hasher.finalize() != *hash {
+            return None;
+        }
+        Some(std::mem::replace(
+            &mut self.data,
+            PartialContent::Memory(Vec::new()),
+        ))
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if let PartialContent::File(path, _) = &self.data {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acks() {
+        let mut content_store = ContentStore::default();
+        let size = 3 * CHUNK_BYTES;
+        let hash = content_store.add(vec![1; size as usize]).unwrap();
+        let mut upload = Upload::new(hash, size);
+        // nothing was sent yet
+        assert!(!upload.acked(size));
+        assert!(upload.next_chunk(&content_store).unwrap().is_some());
+        assert!(upload.next_chunk(&content_store).unwrap().is_some());
+        assert!(!upload.acked(2 * CHUNK_BYTES));
+        // a late acknowledgement
+        assert!(!upload.acked(CHUNK_BYTES));
+        assert_eq!(upload.acked, 2 * CHUNK_BYTES);
+        assert!(upload.next_chunk(&content_store).unwrap().is_some());
+        assert!(upload.next_chunk(&content_store).unwrap().is_none());
+        assert!(upload.acked(size));
+    }
+}
@@ -0,0 +1,250 @@
+//! How messages are framed on the stream between two nodes. Every frame starts with a
+//! magic and carries a checksum, so stray output is detected instead of being decoded.
+
+use anyhow::{bail, Context, Result};
+use bincode::{config::standard, error::DecodeError, Decode};
+use std::io::{BufRead, ErrorKind, Write};
+use tracing::warn;
+
+const MAGIC: [u8; 4] = *b"FYNC";
+const HEADER_LEN: usize = 12;
+/// Output before the first frame that is skipped, e.g. a banner printed by a shell rc
+/// file on the remote.
+const MAX_LEADING_OUTPUT: usize = 64 * 1024;
+/// Memory the decoder may claim for the lengths in a message, per byte of the message.
+/// Containers claim their in-memory size and integers their full width, which exceed what
+/// they take in the frame, but an honest message stays well within this.
+const MAX_CLAIM_RATIO: usize = 64;
+/// Limit for small messages, whose claims aren't held to a multiple of their few bytes.
+const MIN_DECODE_LIMIT_BYTES: usize = 1 << 16;
+
+/// Decodes a message, failing instead of allocating lengths that its own size doesn't
+/// account for. Frames are bounded by `--max-frame-mib`, and so is the memory claimed.
+pub fn decode_message<T: Decode>(payload: &[u8]) -> Result<T> {
+    // bincode takes the limit as a constant, so it is rounded up to a power of two
+    let limit = payload
+        .len()
+        .saturating_mul(MAX_CLAIM_RATIO)
+        .next_power_of_two()
+        .max(MIN_DECODE_LIMIT_BYTES);
+    macro_rules! decode_with_limit {
+        ($($shift:literal)*) => {
+            match limit.trailing_zeros() {
+                $($shift => bincode::decode_from_slice(
+                    payload,
+                    standard().with_limit::<{ 1 << $shift }>(),
+                ),)*
+                _ => bail!("Message of {} bytes is too large to decode", payload.len()),
+            }
+        };
+    }
+    // frames are at most 4 GiB
+    match decode_with_limit!(16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38) {
+        Ok((message, _)) => Ok(message),
+        Err(DecodeError::LimitExceeded) => bail!(
+            "Message of {} bytes claims more than {limit} bytes once decoded",
+            payload.len()
+        ),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    let hash = blake3::hash(payload);
+    u32::from_le_bytes(hash.as_bytes()[..4].try_into().unwrap())
+}
+
+pub struct FrameWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        FrameWriter { writer }
+    }
+
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<()> {
+        let len = u32::try_from(payload.len()).context("Frame too large")?;
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&len.to_le_bytes());
+        header[8..].copy_from_slice(&checksum(payload).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(payload)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    started: bool,
+    max_len: u32,
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader {
+            reader,
+            started: false,
+            max_len: u32::MAX,
+        }
+    }
+
+    /// Fails on frames larger than `max_len`, before reading them.
+    pub fn with_max_len(mut self, max_len: u32) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Returns `None` when the stream ends between frames.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_LEN];
+        if self.started {
+            self.reader
+                .read_exact(&mut header)
+                .context("Stream ended inside a frame header")?;
+            if header[..4] != MAGIC {
+                bail!(
+                    "Stream is out of sync, expected a frame but found {:?}",
+                    String::from_utf8_lossy(&header)
+                );
+            }
+        } else {
+            self.skip_leading_output()?;
+            self.started = true;
+            self.reader
+                .read_exact(&mut header[4..])
+                .context("Stream ended inside a frame header")?;
+        }
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len > self.max_len {
+            bail!(
+                "Other side sent a frame of {len} bytes, more than the limit of {} bytes \
+                 (--max-frame-mib)",
+                self.max_len
+            );
+        }
+        let expected_checksum = u32::from_le_bytes(header[8..].try_into().unwrap());
+        let mut payload = vec![0; len as usize];
+        self.reader
+            .read_exact(&mut payload)
+            .context("Stream ended inside a frame")?;
+        if checksum(&payload) != expected_checksum {
+            bail!("Frame checksum mismatch, the stream is corrupted");
+        }
+        Ok(Some(payload))
+    }
+
+    /// Consumes everything up to and including the first magic. Anything before it is
+    /// reported, since it usually means the remote shell prints something for
+    /// non-interactive sessions.
+    fn skip_leading_output(&mut self) -> Result<()> {
+        let mut output = Vec::new();
+        while !output.ends_with(&MAGIC) {
+            let mut byte = [0];
+            match self.reader.read_exact(&mut byte) {
+                Ok(()) => output.push(byte[0]),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => bail!(
+                    "Other side exited without speaking the fync protocol, it printed: {:?}",
+                    String::from_utf8_lossy(&output)
+                ),
+                Err(e) => return Err(e.into()),
+            }
+            if output.len() > MAX_LEADING_OUTPUT {
+                bail!(
+                    "Other side doesn't speak the fync protocol, it printed: {:?}",
+                    String::from_utf8_lossy(&output[..256])
+                );
+            }
+        }
+        output.truncate(output.len() - MAGIC.len());
+        if !output.is_empty() {
+            warn!(
+                "Skipped unexpected output from the other side: {:?}. Make sure the remote \
+                 shell doesn't print anything for non-interactive sessions.",
+                String::from_utf8_lossy(&output)
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut writer = FrameWriter::new(Vec::new());
+        for payload in payloads {
+            writer.write_frame(payload).unwrap();
+        }
+        writer.writer
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let stream = frames(&[b"hello", b"", b"world"]);
+        let mut reader = FrameReader::new(&stream[..]);
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"hello");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"world");
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_leading_output() {
+        let mut stream = b"Welcome to FYN!\n".to_vec();
+        stream.extend(frames(&[b"hello"]));
+        let mut reader = FrameReader::new(&stream[..]);
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"hello");
+
+        let stream = b"bash: fync: command not found\n";
+        let error = FrameReader::new(&stream[..]).read_frame().unwrap_err();
+        assert!(error.to_string().contains("command not found"));
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut stream = frames(&[b"hello", b"world"]);
+        stream[HEADER_LEN] ^= 1;
+        let mut reader = FrameReader::new(&stream[..]);
+        assert!(reader.read_frame().is_err());
+
+        // output in the middle of the stream
+        let mut stream = frames(&[b"hello"]);
+        stream.extend(b"oops");
+        stream.extend(frames(&[b"world"]));
+        let mut reader = FrameReader::new(&stream[..]);
+        reader.read_frame().unwrap();
+        assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let stream = frames(&[b"hello", b"too long"]);
+        let mut reader = FrameReader::new(&stream[..]).with_max_len(5);
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"hello");
+        let error = reader.read_frame().unwrap_err();
+        assert!(error.to_string().contains("limit of 5 bytes"));
+
+        // a length far beyond the few bytes that follow
+        let payload = bincode::encode_to_vec(u64::MAX, standard()).unwrap();
+        assert!(decode_message::<Vec<u8>>(&payload).is_err());
+        // a few bytes can't claim much more than their own size
+        let payload = bincode::encode_to_vec(200u64 << 20, standard()).unwrap();
+        let error = decode_message::<Vec<u8>>(&payload).unwrap_err();
+        assert!(
+            error.to_string().contains("claims more than 65536 bytes"),
+            "{error}"
+        );
+        let payload = bincode::encode_to_vec(1u64 << 20, standard()).unwrap();
+        let error = decode_message::<Vec<u64>>(&payload).unwrap_err();
+        assert!(error.to_string().contains("claims more than"), "{error}");
+        let payload = bincode::encode_to_vec(vec![1u8; 3], standard()).unwrap();
+        assert_eq!(decode_message::<Vec<u8>>(&payload).unwrap(), [1, 1, 1]);
+    }
+}
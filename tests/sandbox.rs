@@ -0,0 +1,71 @@
+use bincode::config::standard;
+use fync::{AnyNodeMessage, Features, FrameWriter, Hello, NodeInitMessage};
+use std::{
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+/// Runs `fync run-stdio` on `root` until it gives up on the other node, which only says
+/// hello and then hangs up.
+fn run_stdio(root: &Path, sandbox: bool) -> Output {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_fync"));
+    cmd.arg("run-stdio").arg(root);
+    if sandbox {
+        cmd.arg("--sandbox");
+    }
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let hello = AnyNodeMessage::Init(NodeInitMessage::Hello(Hello::new(Features::ALL)));
+    let mut writer = FrameWriter::new(child.stdin.take().unwrap());
+    writer
+        .write_frame(&bincode::encode_to_vec(hello, standard()).unwrap())
+        .unwrap();
+    drop(writer);
+    child.wait_with_output().unwrap()
+}
+
+/// Whether Landlock restricts anything here, checked in a thread of its own as the
+/// restriction can't be lifted.
+fn landlock_supported() -> bool {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_owned();
+    std::thread::spawn(move || fync::sandbox_to_root(&root).unwrap())
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn test_sandbox_rejects_writes_outside_root() {
+    if !landlock_supported() {
+        assert!(
+            std::env::var_os("FYNC_SKIP_LANDLOCK_TEST").is_some(),
+            "Landlock isn't supported by this kernel, set FYNC_SKIP_LANDLOCK_TEST=1 to skip \
+             this test"
+        );
+        return;
+    }
+    // the cache directory leads outside the root, so the node writes there on start
+    let setup = || {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file"), "inside").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join(".fync")).unwrap();
+        (root, outside)
+    };
+
+    // without the sandbox, the write goes through
+    let (root, outside) = setup();
+    run_stdio(root.path(), false);
+    assert_ne!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+
+    let (root, outside) = setup();
+    let output = run_stdio(root.path(), true);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Permission denied"), "{stderr}");
+    assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+}